@@ -14,6 +14,56 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
 
   // Constants from our program
   const MINT_SEED = "mint";
+  const RESERVE_SEED = "reserve";
+  const REGISTRY_SEED = "registry";
+  const POSITION_SEED = "position";
+
+  // Deposited assets: SOL is keyed by the native mint, SPL by its own mint
+  const NATIVE_MINT = new web3.PublicKey("So11111111111111111111111111111111111111112");
+  const splMint = new anchor.web3.PublicKey("token_address");
+
+  // An SPL asset with 6 decimals, whose LP mint keeps 9
+  const sixDecimalMint = new anchor.web3.PublicKey("six_decimal_token_address");
+
+  // An asset mint of the Token-2022 program, whose LP mint is also a Token-2022 mint
+  const TOKEN_2022_PROGRAM_ID = new web3.PublicKey("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+  const token2022Mint = new anchor.web3.PublicKey("token_2022_address");
+
+  // A Token-2022 mint with the transfer-fee extension and a non-zero fee
+  const transferFeeMint = new anchor.web3.PublicKey("transfer_fee_token_address");
+
+  // Account to be used as the authority and payer
+  const authority = provider.wallet.publicKey;
+
+  // Vault account, derived from its creator and vault ID
+  const VAULT_ID = new anchor.BN(0);
+  const [vault] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(VAULT_SEED), authority.toBuffer(), VAULT_ID.toArrayLike(Buffer, "le", 8)],
+    pg.PROGRAM_ID
+  );
+
+  // A second, independent vault of the same authority
+  const secondVaultId = new anchor.BN(1);
+  const [secondVault] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(VAULT_SEED), authority.toBuffer(), secondVaultId.toArrayLike(Buffer, "le", 8)],
+    pg.PROGRAM_ID
+  );
+  const [secondRegistry] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(REGISTRY_SEED), secondVault.toBuffer()],
+    pg.PROGRAM_ID
+  );
+
+  // Accepted SPL mints of the vault
+  const [registry] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(REGISTRY_SEED), vault.toBuffer()],
+    pg.PROGRAM_ID
+  );
+
+  // Deposits of the test wallet in the vault
+  const [position] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(POSITION_SEED), vault.toBuffer(), authority.toBuffer()],
+    pg.PROGRAM_ID
+  );
 
   // Data for testing SPL Token
   const payer = pg.wallet.publicKey;
@@ -22,13 +72,43 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     symbol: "TEST",
     uri: "https://5vfxc4tr6xoy23qefqbj4qx2adzkzapneebanhcalf7myvn5gzja.arweave.net/7UtxcnH13Y1uBCwCnkL6APKsge0hAgacQFl-zFW9NlI",
     decimals: 9,
+    sellerFeeBasisPoints: 0,
+    creators: null,
+    collection: null,
+    freezable: false,
   };
-  let mintAmount = 10;
+  // One LP mint per vault and deposited asset
   const [mint] = web3.PublicKey.findProgramAddressSync(
-    [Buffer.from(MINT_SEED)],
+    [Buffer.from(MINT_SEED), vault.toBuffer(), NATIVE_MINT.toBuffer()],
+    pg.PROGRAM_ID
+  );
+  const [splLpMint] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(MINT_SEED), vault.toBuffer(), splMint.toBuffer()],
+    pg.PROGRAM_ID
+  );
+
+  const [token2022LpMint] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(MINT_SEED), vault.toBuffer(), token2022Mint.toBuffer()],
+    pg.PROGRAM_ID
+  );
+
+  // Bookkeeping of the SPL asset held by the vault
+  const [splReserve] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(RESERVE_SEED), vault.toBuffer(), splMint.toBuffer()],
+    pg.PROGRAM_ID
+  );
+  const [token2022Reserve] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from(RESERVE_SEED), vault.toBuffer(), token2022Mint.toBuffer()],
     pg.PROGRAM_ID
   );
 
+  // Associated token accounts of Token-2022 mints are derived with the Token-2022 program
+  const token2022Address = (mint, owner) =>
+    web3.PublicKey.findProgramAddressSync(
+      [owner.toBuffer(), TOKEN_2022_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+      anchor.utils.token.ASSOCIATED_PROGRAM_ID
+    )[0];
+
   const [metadataAddress] = web3.PublicKey.findProgramAddressSync(
     [
       Buffer.from(METADATA_SEED),
@@ -38,20 +118,83 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     TOKEN_METADATA_PROGRAM_ID
   );
 
-  // Account to be used as the authority and payer
-  const authority = provider.wallet.publicKey;
+  // First deposits mint 1 whole LP token per whole asset token
+  const scaleAmount = (amount, fromDecimals, toDecimals) =>
+    toDecimals >= fromDecimals
+      ? amount.mul(new anchor.BN(10).pow(new anchor.BN(toDecimals - fromDecimals)))
+      : amount.div(new anchor.BN(10).pow(new anchor.BN(fromDecimals - toDecimals)));
 
-  // Vault account
-  let vault;
+  // Expect a transaction to fail with the given Anchor error code
+  const expectError = async (promise, code) => {
+    try {
+      await promise;
+    } catch (err) {
+      assert.equal(err.error.errorCode.code, code);
+      return;
+    }
+    assert.fail(`Transaction should have failed with ${code}.`);
+  };
 
-  // Test initialize the vault
-  it("Initialize Vault", async () => {
-    // Derive the vault PDA (Program Derived Address)
-    [vault] = anchor.web3.PublicKey.findProgramAddressSync(
-      [Buffer.from(VAULT_SEED)],
-      program.programId
+  // Derive the accounts of another vault of the test wallet and create the missing ones.
+  // Passing token metadata also sets up the vault's SOL LP mint.
+  const setUpVault = async (vaultId, tokenMetadata = null) => {
+    const [vaultAccount] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(VAULT_SEED), authority.toBuffer(), vaultId.toArrayLike(Buffer, "le", 8)],
+      pg.PROGRAM_ID
+    );
+    const [vaultRegistry] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(REGISTRY_SEED), vaultAccount.toBuffer()],
+      pg.PROGRAM_ID
     );
+    const [vaultMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), vaultAccount.toBuffer(), NATIVE_MINT.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [vaultPosition] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(POSITION_SEED), vaultAccount.toBuffer(), authority.toBuffer()],
+      pg.PROGRAM_ID
+    );
+
+    if (!(await provider.connection.getAccountInfo(vaultAccount))) {
+      await program.methods
+        .initVault(vaultId)
+        .accounts({
+          authority,
+          vaultAccount,
+          registry: vaultRegistry,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+    if (tokenMetadata && !(await provider.connection.getAccountInfo(vaultMint))) {
+      const [vaultMetadata] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from(METADATA_SEED), TOKEN_METADATA_PROGRAM_ID.toBuffer(), vaultMint.toBuffer()],
+        TOKEN_METADATA_PROGRAM_ID
+      );
+      await program.methods
+        .initToken(tokenMetadata)
+        .accounts({
+          metadata: vaultMetadata,
+          authority,
+          vaultAccount,
+          registry: vaultRegistry,
+          assetMint: NATIVE_MINT,
+          mint: vaultMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+
+    return { vault: vaultAccount, registry: vaultRegistry, mint: vaultMint, position: vaultPosition };
+  };
 
+  // Test initialize the vault
+  it("Initialize Vault", async () => {
     // Check if the vault is already initialized
     const vaultAccountInfo = await provider.connection.getAccountInfo(vault);
     if (vaultAccountInfo) {
@@ -65,10 +208,11 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
 
     // Prepare the context for the initialize instruction
     const tx = await program.methods
-      .initVault()
+      .initVault(VAULT_ID)
       .accounts({
         authority,
         vaultAccount: vault,
+        registry,
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
@@ -80,6 +224,118 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     const vaultAccount = await program.account.vault.fetch(vault);
     assert.ok(vaultAccount.authority.equals(authority));
     assert.equal(vaultAccount.balance.toNumber(), 0);
+    assert.ok(vaultAccount.creator.equals(authority));
+    assert.ok(vaultAccount.vaultId.eq(VAULT_ID));
+  });
+
+  // Test that one authority can run several independent vaults
+  it("Initialize Second Vault", async () => {
+    assert.ok(!secondVault.equals(vault));
+
+    if (await provider.connection.getAccountInfo(secondVault)) {
+      console.log("Second vault is already initialized. Skipping initialization.");
+      return;
+    }
+
+    await program.methods
+      .initVault(secondVaultId)
+      .accounts({
+        authority,
+        vaultAccount: secondVault,
+        registry: secondRegistry,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const secondVaultAccount = await program.account.vault.fetch(secondVault);
+    assert.ok(secondVaultAccount.vaultId.eq(secondVaultId));
+    assert.equal(secondVaultAccount.balance.toNumber(), 0);
+  });
+
+  // The original program's single vault, created by its `init_vault` on this cluster, is
+  // upgraded in place and then only pays out to its LP holders. The unit tests in lib.rs build a
+  // version 1 account for the layout change itself, as the program no longer creates one.
+  it("Migrate the original vault", async () => {
+    const LEGACY_SIZE = 8 + 32 + 8;
+    const VAULT_SIZE = 8 + 1 + 32 * 8 + 8 * 2 + 6 + 4 + 80 + 64;
+    const [legacyVault] = web3.PublicKey.findProgramAddressSync([Buffer.from(VAULT_SEED)], pg.PROGRAM_ID);
+    const [legacyMint] = web3.PublicKey.findProgramAddressSync([Buffer.from(MINT_SEED)], pg.PROGRAM_ID);
+    const migrate = (signer = pg.wallet.keypair) =>
+      program.methods
+        .migrateVault()
+        .accounts({
+          authority: signer.publicKey,
+          vaultAccount: legacyVault,
+          lpMint: legacyMint,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([signer])
+        .rpc();
+
+    const before = await pg.connection.getAccountInfo(legacyVault);
+    if (!before) {
+      console.log("This cluster has no vault of the original program. Skipping.");
+      return;
+    }
+    if (before.data.length === LEGACY_SIZE) {
+      const outsider = web3.Keypair.generate();
+      await provider.sendAndConfirm(
+        new web3.Transaction().add(
+          web3.SystemProgram.transfer({
+            fromPubkey: payer,
+            toPubkey: outsider.publicKey,
+            lamports: web3.LAMPORTS_PER_SOL / 100,
+          })
+        )
+      );
+      await expectError(migrate(outsider), "InvalidMigrationAccount");
+
+      await migrate();
+      const after = await pg.connection.getAccountInfo(legacyVault);
+      assert.equal(after.data.length, VAULT_SIZE);
+      // The authority pays the added rent, so the deposits above the minimum are unchanged
+      const addedRent =
+        (await pg.connection.getMinimumBalanceForRentExemption(VAULT_SIZE)) -
+        (await pg.connection.getMinimumBalanceForRentExemption(LEGACY_SIZE));
+      assert.equal(after.lamports - before.lamports, addedRent);
+
+      const migrated = await program.account.vault.fetch(legacyVault);
+      assert.ok(migrated.authority.equals(new web3.PublicKey(before.data.subarray(8, 40))));
+      assert.ok(migrated.balance.eq(new anchor.BN(before.data.subarray(40, 48), "le")));
+    }
+
+    // Migrated once, the vault winds down and cannot be migrated again
+    const migrated = await program.account.vault.fetch(legacyVault);
+    assert.equal(migrated.version, 2);
+    assert.ok(migrated.exitMode);
+    await expectError(migrate(), "UnsupportedAccountVersion");
+
+    // Its LP holders redeem 1 lamport per LP token
+    const source = await anchor.utils.token.associatedAddress({ mint: legacyMint, owner: authority });
+    if (!(await pg.connection.getAccountInfo(source)) || migrated.balance.isZero()) {
+      return;
+    }
+    const redeem = (amount) =>
+      program.methods
+        .redeemLegacy(amount)
+        .accounts({
+          owner: authority,
+          vaultAccount: legacyVault,
+          mint: legacyMint,
+          source,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    await expectError(redeem(migrated.balance.addn(1)), "InsufficientBalance");
+    const lpBefore = new anchor.BN((await pg.connection.getTokenAccountBalance(source)).value.amount);
+    if (lpBefore.isZero()) {
+      return;
+    }
+    await redeem(new anchor.BN(1));
+    assert.ok((await program.account.vault.fetch(legacyVault)).balance.eq(migrated.balance.subn(1)));
+    const lpAfter = new anchor.BN((await pg.connection.getTokenAccountBalance(source)).value.amount);
+    assert.ok(lpAfter.eq(lpBefore.subn(1)));
   });
 
   // Test initialize the token
@@ -97,6 +353,10 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
   
     const context = {
       metadata: metadataAddress,
+      authority,
+      vaultAccount: vault,
+      registry,
+      assetMint: NATIVE_MINT,
       mint,
       payer,
       rent: web3.SYSVAR_RENT_PUBKEY,
@@ -114,9 +374,279 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     console.log(`Initialized Token.\nTransaction Signature: https://explorer.solana.com/tx/${txHash}?cluster=devnet`);
     const newInfo = await pg.connection.getAccountInfo(mint);
     assert(newInfo, "  Mint should be initialized.");
+
+    // The LP mint is bound to the vault
+    const vaultAccount = await program.account.vault.fetch(vault);
+    assert.ok(vaultAccount.lpMint.equals(mint));
+    assert.equal(vaultAccount.lpDecimals, metadata.decimals);
   });
 
-  
+  // Only the vault authority can set up LP mints
+  it("Initialize Token rejects non-authority", async () => {
+    const outsider = web3.Keypair.generate();
+    const [secondVaultMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), secondVault.toBuffer(), NATIVE_MINT.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [secondVaultMetadata] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(METADATA_SEED),
+        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+        secondVaultMint.toBuffer(),
+      ],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+    await expectError(
+      program.methods
+        .initToken(metadata)
+        .accounts({
+          metadata: secondVaultMetadata,
+          authority: outsider.publicKey,
+          vaultAccount: secondVault,
+          registry: secondRegistry,
+          assetMint: NATIVE_MINT,
+          mint: secondVaultMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .signers([outsider])
+        .rpc(),
+      "ConstraintHasOne"
+    );
+  });
+
+  // Metaplex settings are checked before the LP token is created
+  it("Initialize Token rejects invalid metadata", async () => {
+    const [secondVaultMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), secondVault.toBuffer(), NATIVE_MINT.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [secondVaultMetadata] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(METADATA_SEED),
+        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+        secondVaultMint.toBuffer(),
+      ],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const initWith = (params) =>
+      program.methods
+        .initToken({ ...metadata, ...params })
+        .accounts({
+          metadata: secondVaultMetadata,
+          authority,
+          vaultAccount: secondVault,
+          registry: secondRegistry,
+          assetMint: NATIVE_MINT,
+          mint: secondVaultMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(initWith({ sellerFeeBasisPoints: 10001 }), "InvalidSellerFee");
+    await expectError(
+      initWith({
+        creators: [
+          { address: authority, share: 60 },
+          { address: web3.Keypair.generate().publicKey, share: 30 },
+        ],
+      }),
+      "InvalidCreatorShares"
+    );
+    await expectError(
+      initWith({ collection: web3.Keypair.generate().publicKey }),
+      "MissingCollectionAccounts"
+    );
+  });
+
+  // Test updating the LP token metadata through the mint PDA
+  it("Update Token Metadata", async () => {
+    const name = "Wallet Vault SOL LP";
+    const context = {
+      authority,
+      vaultAccount: vault,
+      assetMint: NATIVE_MINT,
+      mint,
+      metadata: metadataAddress,
+      tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+    };
+    const params = {
+      name,
+      symbol: null,
+      uri: null,
+      newUpdateAuthority: null,
+      isMutable: null,
+    };
+
+    await program.methods.updateTokenMetadata(params).accounts(context).rpc();
+
+    const info = await pg.connection.getAccountInfo(metadataAddress);
+    assert.ok(info.data.includes(Buffer.from(name)), "Metadata should hold the new name.");
+
+    // Only the vault authority can update it
+    const outsider = web3.Keypair.generate();
+    await expectError(
+      program.methods
+        .updateTokenMetadata(params)
+        .accounts({ ...context, authority: outsider.publicKey })
+        .signers([outsider])
+        .rpc(),
+      "ConstraintHasOne"
+    );
+  });
+
+  // Test registering the SPL token as an accepted asset
+  it("Add SPL Asset", async () => {
+    const registryAccount = await program.account.assetRegistry.fetch(registry);
+    if (registryAccount.assets.some((asset) => asset.mint.equals(splMint))) {
+      console.log("SPL asset is already registered. Skipping registration.");
+      return;
+    }
+
+    await program.methods
+      .addAsset({
+        enabled: true,
+        depositCap: new anchor.BN(0), // No cap
+        minDeposit: new anchor.BN(1),
+      })
+      .accounts({
+        assetManager: authority,
+        vaultAccount: vault,
+        registry,
+        assetMint: splMint,
+        lpMint: splLpMint,
+      })
+      .rpc();
+
+    const updated = await program.account.assetRegistry.fetch(registry);
+    const config = updated.assets.find((asset) => asset.mint.equals(splMint));
+    assert.ok(config, "SPL asset should be registered.");
+    assert.ok(config.enabled);
+    const mintInfo = await pg.connection.getParsedAccountInfo(splMint);
+    assert.equal(config.assetDecimals, mintInfo.value.data.parsed.info.decimals);
+  });
+
+  // Test initialize the LP token of the SPL asset
+  it("Initialize SPL LP Token", async () => {
+    console.log("SPL LP Token ID (Mint Address):", splLpMint.toString());
+
+    const info = await pg.connection.getAccountInfo(splLpMint);
+    if (info) {
+      console.log("SPL LP Token is already initialized! Skipping initialization.");
+      return;
+    }
+
+    const [splLpMetadata] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(METADATA_SEED),
+        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+        splLpMint.toBuffer(),
+      ],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+    const txHash = await program.methods
+      .initToken(metadata)
+      .accounts({
+        metadata: splLpMetadata,
+        authority,
+        vaultAccount: vault,
+        registry,
+        assetMint: splMint,
+        mint: splLpMint,
+        payer,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .rpc();
+
+    await pg.connection.confirmTransaction(txHash, 'finalized');
+    const newInfo = await pg.connection.getAccountInfo(splLpMint);
+    assert(newInfo, "  SPL LP Mint should be initialized.");
+
+    // The LP mint is bound to the asset's registry entry
+    const registryAccount = await program.account.assetRegistry.fetch(registry);
+    const config = registryAccount.assets.find((asset) => asset.mint.equals(splMint));
+    assert.ok(config.lpMint.equals(splLpMint));
+  });
+
+  // The LP mint of a removed asset stays on chain and is bound again when the asset returns
+  it("Re-added asset keeps its LP mint", async () => {
+    const { vault: readdedVault, registry: readdedRegistry } = await setUpVault(new anchor.BN(6));
+    const [readdedLpMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), readdedVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [readdedReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), readdedVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const assetAccounts = {
+      assetManager: authority,
+      vaultAccount: readdedVault,
+      registry: readdedRegistry,
+      assetMint: splMint,
+    };
+    const addAsset = () =>
+      program.methods
+        .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+        .accounts({ ...assetAccounts, lpMint: readdedLpMint })
+        .rpc();
+    const findConfig = async () =>
+      (await program.account.assetRegistry.fetch(readdedRegistry)).assets.find((asset) =>
+        asset.mint.equals(splMint)
+      );
+
+    if (!(await findConfig())) {
+      await addAsset();
+    }
+    if (!(await pg.connection.getAccountInfo(readdedLpMint))) {
+      const [lpMetadata] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from(METADATA_SEED), TOKEN_METADATA_PROGRAM_ID.toBuffer(), readdedLpMint.toBuffer()],
+        TOKEN_METADATA_PROGRAM_ID
+      );
+      await program.methods
+        .initToken(metadata)
+        .accounts({
+          metadata: lpMetadata,
+          authority,
+          vaultAccount: readdedVault,
+          registry: readdedRegistry,
+          assetMint: splMint,
+          mint: readdedLpMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+
+    // The removed entry took its LP mint binding with it
+    await program.methods
+      .removeAsset()
+      .accounts({ ...assetAccounts, reserve: readdedReserve })
+      .rpc();
+    assert.ok(!(await findConfig()));
+
+    await addAsset();
+    const config = await findConfig();
+    assert.ok(config.lpMint.equals(readdedLpMint));
+    assert.equal(config.lpDecimals, metadata.decimals);
+  });
+
+
   // Test deposit
   it("Deposit Method", async () => {
     const depositSolAmount = 1;
@@ -132,12 +662,21 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     let initialBalance;
     try {
       const balance = (await pg.connection.getTokenAccountBalance(destination))
-      initialBalance = balance.value.uiAmount;
+      initialBalance = new anchor.BN(balance.value.amount);
     } catch {
       // Token account not yet initiated has 0 balance
-      initialBalance = 0;
+      initialBalance = new anchor.BN(0);
     }
-    console.log("Token Balance: ", initialBalance);
+    console.log("Token Balance: ", initialBalance.toString());
+
+    // LP tokens are priced from the vault's assets and LP supply before the deposit
+    const vaultBefore = await program.account.vault.fetch(vault);
+    const supplyBefore = new anchor.BN(
+      (await pg.connection.getTokenSupply(mint)).value.amount
+    );
+    const expectedLp = supplyBefore.isZero()
+      ? new anchor.BN(depositAmount)
+      : new anchor.BN(depositAmount).mul(supplyBefore).div(vaultBefore.balance);
 
     // Token Context
     const context = {
@@ -158,6 +697,7 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
         vaultAccount: vault,
         mint: context.mint,
         destination: destination,
+        position: position,
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
         systemProgram: web3.SystemProgram.programId,
         tokenProgram: context.tokenProgram,
@@ -167,19 +707,18 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
 
     await pg.connection.confirmTransaction(tx);
 
-    const postBalance = (
+    const postBalance = new anchor.BN((
       await pg.connection.getTokenAccountBalance(destination)
-    ).value.uiAmount;
+    ).value.amount);
 
-    assert.equal(
-      initialBalance + (mintAmount / 10),
-      postBalance,
-      "Post balance should equal initial plus mint amount"
+    assert.ok(
+      initialBalance.add(expectedLp).eq(postBalance),
+      "Post balance should equal initial plus the proportional LP amount"
     );
 
     console.log("Token ID (Mint Address):", mint.toString());
     console.log("Metadata Account:", metadataAddress.toString());
-    console.log("Token Balance:", postBalance);
+    console.log("Token Balance:", postBalance.toString());
 
     console.log(
       "Deposited ", depositSolAmount, " SOL into the vault.\nTransaction Signature:", 
@@ -189,6 +728,13 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     // Verify that the vault's balance has increased by the deposited amount
     const vaultAccount = await program.account.vault.fetch(vault);
     console.log("Vault Balance: ", vaultAccount.balance.toNumber());
+    assert.ok(vaultAccount.balance.eq(vaultBefore.balance.addn(depositAmount)));
+
+    // The deposit is recorded on the user's position
+    const positionAccount = await program.account.userPosition.fetch(position);
+    assert.ok(positionAccount.owner.equals(authority));
+    const solPosition = positionAccount.assets.find((asset) => asset.assetMint.equals(NATIVE_MINT));
+    assert.ok(solPosition.principal.gten(depositAmount));
   });
 
   // Test withdraw
@@ -207,6 +753,19 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
       provider.wallet.publicKey
     );
 
+    // LP tokens to burn are rounded up in favour of the vault
+    const initialLpBalance = new anchor.BN(
+      (await pg.connection.getTokenAccountBalance(source)).value.amount
+    );
+    const vaultBefore = await program.account.vault.fetch(vault);
+    const supplyBefore = new anchor.BN(
+      (await pg.connection.getTokenSupply(mint)).value.amount
+    );
+    const expectedBurn = new anchor.BN(amount)
+      .mul(supplyBefore)
+      .add(vaultBefore.balance.subn(1))
+      .div(vaultBefore.balance);
+
     // Token context
     const context = {
       metadata: metadataAddress,
@@ -225,7 +784,8 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
       .accounts({
         vaultAccount: vault,
         user: authority, // Authority of the vault
-        authority: authority,
+        owner: authority,
+        position: position,
         mint: context.mint,
         source: source,
         tokenProgram: context.tokenProgram,
@@ -237,10 +797,14 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
       });
 
     // User LP token balance
-    const postBalance = (
+    const postBalance = new anchor.BN((
       await pg.connection.getTokenAccountBalance(source)
-    ).value.uiAmount;
-    console.log("Token Balance: ", postBalance);
+    ).value.amount);
+    console.log("Token Balance: ", postBalance.toString());
+    assert.ok(
+      initialLpBalance.sub(expectedBurn).eq(postBalance),
+      "Post LP balance should equal initial minus the proportional burn amount"
+    );
     
     console.log(
       "Withdrawn ", withdrawAmount, " SOL from the vault. \nTransaction Signature:", 
@@ -263,87 +827,401 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     assert.ok(roundedExpectedBalance === roundedFinalBalance); 
   });
 
-  // Test deposit SPL token
-  it("Deposit SPL Method", async () => {
-    const splMint = new anchor.web3.PublicKey("token_address");
-    let mintAmount = 1;
-    const depositAmount = 1 * (10 ** 9); // Assuming the SPL token has 9 decimal places
-    
-    // Associated Token Account for User
-    const userAta = await anchor.utils.token.associatedAddress({
-        mint: splMint,
-        owner: payer, // payer is the user's publicKey
-    });
-    console.log("User's Associated Token Account: ", userAta.toString());
-
-    // Associated Token Account for Vault
-    const vaultAta = await anchor.utils.token.associatedAddress({
-        mint: splMint,
-        owner: vault, // vault is the vault's publicKey (PDA)
-    });
-    console.log("Vault's Associated Token Account: ", vaultAta.toString());
-    
-    console.log("LP Token mint: ", mint.toString());
-    const userLpAta = await anchor.utils.token.associatedAddress({
-      mint: mint,
-      owner: payer,
-    });
-    console.log("User's LP Token ATA: ", userLpAta.toString());
-
-    // LP Token Balance
-    let initialLpBalance;
-    try {
-      const balance = (await pg.connection.getTokenAccountBalance(userLpAta))
-      initialLpBalance = balance.value.uiAmount;
-    } catch {
-      // Token account not yet initiated has 0 balance
-      initialLpBalance = 0;
-    }
-    console.log("Initial User LP Balance: ", initialLpBalance);
-    
-    // Fetch the initial balance of the user and vault
-    let initialUserBalance = (await pg.connection.getTokenAccountBalance(userAta)).value.uiAmount;
-    let initialVaultBalance = (await pg.connection.getTokenAccountBalance(vaultAta)).value.uiAmount;
-    console.log("Initial User SPL Balance:", initialUserBalance);
-    console.log("Initial Vault SPL Balance:", initialVaultBalance);
-
-    // Ensure the user has enough tokens for the deposit
-    if (initialUserBalance < (depositAmount / (10 ** 9))) {
-        throw new Error("Insufficient SPL tokens in user's account for deposit.");
-    }
-
-    // Prepare the context for the deposit_spl instruction
-    const tx = await program.methods
-        .depositSpl(new anchor.BN(depositAmount)) // Using the correct deposit amount
+  // Test that withdrawals never take the vault below its rent-exempt minimum
+  it("Withdraw respects rent-exempt floor", async () => {
+    const source = await anchor.utils.token.associatedAddress({ mint, owner: payer });
+    const info = await provider.connection.getAccountInfo(vault);
+    const rentExempt = await provider.connection.getMinimumBalanceForRentExemption(
+      info.data.length
+    );
+    const available = info.lamports - rentExempt;
+    console.log("Vault rent-exempt minimum:", rentExempt, "Available:", available);
+    assert.ok(available >= 0, "Vault should stay rent exempt.");
+    const withdraw = (amount) =>
+      program.methods
+        .withdraw(new anchor.BN(amount))
         .accounts({
-          user: payer,
-          userAta: userAta, // user's token account (SPL)
           vaultAccount: vault,
-          vaultAta: vaultAta, // vault's token account (SPL)
-          mint: mint,
-          userLpAta: userLpAta,
-          rent: web3.SYSVAR_RENT_PUBKEY,
-          systemProgram: web3.SystemProgram.programId,
+          user: authority,
+          owner: authority,
+          position: position,
+          mint,
+          source,
           tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
-          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
         })
         .rpc();
 
-    await sleep(1000);
-    console.log(`Deposit Transaction Signature: https://explorer.solana.com/tx/${tx}?cluster=devnet`);
+    // One lamport above the available liquidity is rejected
+    await expectError(withdraw(available + 1), "InsufficientLiquidity");
 
-    // Fetch the balances after the deposit
-    const finalUserBalance = (await pg.connection.getTokenAccountBalance(userAta)).value.uiAmount;
-    const finalVaultBalance = (await pg.connection.getTokenAccountBalance(vaultAta)).value.uiAmount;
+    // Exactly the available liquidity is paid out, down to the rent-exempt minimum.
+    // Unsynced lamports are credited first so the recorded balance covers it.
+    await program.methods
+      .syncBalance()
+      .accounts({ signer: authority, vaultAccount: vault, treasury: null })
+      .rpc();
+    await withdraw(available);
+    assert.equal(await provider.connection.getBalance(vault), rentExempt);
+    assert.equal((await program.account.vault.fetch(vault)).balance.toNumber(), 0);
 
-    console.log("Final User SPL Balance:", finalUserBalance);
-    console.log("Final Vault SPL Balance:", finalVaultBalance);
+    // Put the liquidity back for the later tests
+    await program.methods
+      .deposit(new anchor.BN(available))
+      .accounts({
+        user: authority,
+        vaultAccount: vault,
+        mint,
+        destination: source,
+        position: position,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+  });
+
+  // Test that SOL sent straight to the vault is credited to LP holders by sync_balance
+  it("Sync Balance Method", async () => {
+    const donation = 1000;
+    const transferTx = new web3.Transaction().add(
+      web3.SystemProgram.transfer({ fromPubkey: payer, toPubkey: vault, lamports: donation })
+    );
+    await provider.sendAndConfirm(transferTx);
+
+    // The donation is not part of the recorded balance yet
+    const vaultBefore = await program.account.vault.fetch(vault);
+
+    await program.methods
+      .syncBalance()
+      .accounts({ signer: authority, vaultAccount: vault, treasury: null })
+      .rpc();
+
+    const info = await provider.connection.getAccountInfo(vault);
+    const rentExempt = await provider.connection.getMinimumBalanceForRentExemption(
+      info.data.length
+    );
+    const vaultAfter = await program.account.vault.fetch(vault);
+    assert.equal(vaultAfter.balance.toNumber(), info.lamports - rentExempt);
+    assert.ok(vaultAfter.balance.gte(vaultBefore.balance.addn(donation)));
+
+    // A treasury policy needs a treasury
+    await expectError(
+      program.methods
+        .setSurplusPolicy({ treasury: {} }, web3.PublicKey.default)
+        .accounts({ feeManager: authority, vaultAccount: vault })
+        .rpc(),
+      "TreasuryNotSet"
+    );
+  });
+
+  // A first depositor cannot inflate the LP price with a donation, as only the authority and
+  // the fee manager credit surplus as yield
+  it("Sync Balance rejects a donation attack", async () => {
+    const attacker = web3.Keypair.generate();
+    const { vault: attackedVault, mint: attackedMint } = await setUpVault(new anchor.BN(Date.now()), metadata);
+    const deposit = async (user, amount) =>
+      program.methods
+        .deposit(new anchor.BN(amount))
+        .accounts({
+          user: user.publicKey,
+          vaultAccount: attackedVault,
+          mint: attackedMint,
+          destination: await anchor.utils.token.associatedAddress({ mint: attackedMint, owner: user.publicKey }),
+          position: web3.PublicKey.findProgramAddressSync(
+            [Buffer.from(POSITION_SEED), attackedVault.toBuffer(), user.publicKey.toBuffer()],
+            pg.PROGRAM_ID
+          )[0],
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .signers([user])
+        .rpc();
+
+    // The attacker deposits 1 lamport, then sends a donation straight to the vault
+    const donation = web3.LAMPORTS_PER_SOL / 100;
+    await provider.sendAndConfirm(
+      new web3.Transaction().add(
+        web3.SystemProgram.transfer({
+          fromPubkey: payer,
+          toPubkey: attacker.publicKey,
+          lamports: web3.LAMPORTS_PER_SOL / 20,
+        })
+      )
+    );
+    await deposit(attacker, 1);
+    await provider.sendAndConfirm(
+      new web3.Transaction().add(
+        web3.SystemProgram.transfer({ fromPubkey: attacker.publicKey, toPubkey: attackedVault, lamports: donation })
+      ),
+      [attacker]
+    );
+    await expectError(
+      program.methods
+        .syncBalance()
+        .accounts({ signer: attacker.publicKey, vaultAccount: attackedVault, treasury: null })
+        .signers([attacker])
+        .rpc(),
+      "UnauthorizedYieldSync"
+    );
+
+    // The next depositor is still priced from the recorded balance and loses nothing
+    const victimDeposit = donation + 1;
+    await deposit(pg.wallet.keypair, victimDeposit);
+    const victimLp = await pg.connection.getTokenAccountBalance(
+      await anchor.utils.token.associatedAddress({ mint: attackedMint, owner: payer })
+    );
+    assert.equal(victimLp.value.amount, victimDeposit.toString());
+  });
+
+  // Test SOL deposits held as wrapped SOL, on the second vault
+  it("Wrapped SOL Deposit and Withdraw", async () => {
+    const [secondVaultMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), secondVault.toBuffer(), NATIVE_MINT.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [secondVaultMetadata] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(METADATA_SEED),
+        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+        secondVaultMint.toBuffer(),
+      ],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const [wsolReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), secondVault.toBuffer(), NATIVE_MINT.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [secondPosition] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(POSITION_SEED), secondVault.toBuffer(), authority.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [unwrapAccount] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("unwrap"), secondVault.toBuffer(), authority.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const vaultAta = await anchor.utils.token.associatedAddress({
+      mint: NATIVE_MINT,
+      owner: secondVault,
+    });
+    const lpAta = await anchor.utils.token.associatedAddress({
+      mint: secondVaultMint,
+      owner: authority,
+    });
+
+    if (!(await pg.connection.getAccountInfo(secondVaultMint))) {
+      await program.methods
+        .initToken(metadata)
+        .accounts({
+          metadata: secondVaultMetadata,
+          authority,
+          vaultAccount: secondVault,
+          registry: secondRegistry,
+          assetMint: NATIVE_MINT,
+          mint: secondVaultMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+    if (!(await program.account.vault.fetch(secondVault)).wrapSol) {
+      await program.methods
+        .setSolWrapping(true)
+        .accounts({ authority, vaultAccount: secondVault, mint: secondVaultMint })
+        .rpc();
+    }
+
+    // The lamport path is closed once SOL is wrapped
+    await expectError(
+      program.methods
+        .deposit(new anchor.BN(1000))
+        .accounts({
+          user: authority,
+          vaultAccount: secondVault,
+          mint: secondVaultMint,
+          destination: lpAta,
+          position: secondPosition,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc(),
+      "WrappedSolEnabled"
+    );
+    // Lamports sent to the vault are not credited either
+    await expectError(
+      program.methods
+        .syncBalance()
+        .accounts({ signer: authority, vaultAccount: secondVault, treasury: null })
+        .rpc(),
+      "WrappedSolEnabled"
+    );
+
+    const amount = new anchor.BN(web3.LAMPORTS_PER_SOL / 10);
+    await program.methods
+      .depositWrappedSol(amount)
+      .accounts({
+        user: authority,
+        vaultAccount: secondVault,
+        nativeMint: NATIVE_MINT,
+        vaultAta,
+        mint: secondVaultMint,
+        reserve: wsolReserve,
+        destination: lpAta,
+        position: secondPosition,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+
+    // The SOL is held as wrapped SOL and tracked by the native mint's reserve
+    let reserveAccount = await program.account.assetReserve.fetch(wsolReserve);
+    assert.ok(reserveAccount.balance.eq(amount));
+    const wrapped = (await pg.connection.getTokenAccountBalance(vaultAta)).value.amount;
+    assert.equal(wrapped, amount.toString());
+    const lpBalance = (await pg.connection.getTokenAccountBalance(lpAta)).value.amount;
+    assert.equal(lpBalance, amount.toString());
+
+    // Withdrawing unwraps back to native SOL
+    const solBefore = await provider.connection.getBalance(authority);
+    await program.methods
+      .withdrawWrappedSol(amount)
+      .accounts({
+        user: authority,
+        vault: secondVault,
+        owner: authority,
+        position: secondPosition,
+        nativeMint: NATIVE_MINT,
+        vaultAta,
+        unwrapAccount,
+        mint: secondVaultMint,
+        reserve: wsolReserve,
+        source: lpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const solAfter = await provider.connection.getBalance(authority);
+    // Only the transaction fee is missing
+    assert.ok(solAfter > solBefore + amount.toNumber() - 10000);
+    reserveAccount = await program.account.assetReserve.fetch(wsolReserve);
+    assert.equal(reserveAccount.balance.toNumber(), 0);
+    assert.equal((await pg.connection.getTokenAccountBalance(vaultAta)).value.amount, "0");
+    assert.equal(await pg.connection.getAccountInfo(unwrapAccount), null);
+  });
+
+  // Test deposit SPL token
+  it("Deposit SPL Method", async () => {
+    const depositAmount = 1 * (10 ** 9); // Assuming the SPL token has 9 decimal places
+    
+    // Associated Token Account for User
+    const userAta = await anchor.utils.token.associatedAddress({
+        mint: splMint,
+        owner: payer, // payer is the user's publicKey
+    });
+    console.log("User's Associated Token Account: ", userAta.toString());
+
+    // Associated Token Account for Vault
+    const vaultAta = await anchor.utils.token.associatedAddress({
+        mint: splMint,
+        owner: vault, // vault is the vault's publicKey (PDA)
+    });
+    console.log("Vault's Associated Token Account: ", vaultAta.toString());
+    
+    console.log("LP Token mint: ", splLpMint.toString());
+    const userLpAta = await anchor.utils.token.associatedAddress({
+      mint: splLpMint,
+      owner: payer,
+    });
+    console.log("User's LP Token ATA: ", userLpAta.toString());
+
+    // LP Token Balance
+    let initialLpBalance;
+    try {
+      const balance = (await pg.connection.getTokenAccountBalance(userLpAta))
+      initialLpBalance = new anchor.BN(balance.value.amount);
+    } catch {
+      // Token account not yet initiated has 0 balance
+      initialLpBalance = new anchor.BN(0);
+    }
+    console.log("Initial User LP Balance: ", initialLpBalance.toString());
+    
+    // Fetch the initial balance of the user and vault
+    let initialUserBalance = (await pg.connection.getTokenAccountBalance(userAta)).value.uiAmount;
+    let initialVaultBalance;
+    try {
+      initialVaultBalance = (await pg.connection.getTokenAccountBalance(vaultAta)).value.uiAmount;
+    } catch {
+      // The vault's ATA is created by the first deposit
+      initialVaultBalance = 0;
+    }
+    console.log("Initial User SPL Balance:", initialUserBalance);
+    console.log("Initial Vault SPL Balance:", initialVaultBalance);
+
+    // Ensure the user has enough tokens for the deposit
+    if (initialUserBalance < (depositAmount / (10 ** 9))) {
+        throw new Error("Insufficient SPL tokens in user's account for deposit.");
+    }
+
+    // LP tokens are priced from the reserve's recorded balance, not the vault ATA amount
+    const reserveBefore = await program.account.assetReserve.fetchNullable(splReserve);
+    const reserveBalanceBefore = reserveBefore ? reserveBefore.balance : new anchor.BN(0);
+    const supplyBefore = new anchor.BN(
+      (await pg.connection.getTokenSupply(splLpMint)).value.amount
+    );
+    const splConfig = (await program.account.assetRegistry.fetch(registry)).assets
+      .find((asset) => asset.mint.equals(splMint));
+    const expectedLp = supplyBefore.isZero()
+      ? scaleAmount(new anchor.BN(depositAmount), splConfig.assetDecimals, splConfig.lpDecimals)
+      : new anchor.BN(depositAmount).mul(supplyBefore).div(reserveBalanceBefore);
+
+    // Prepare the context for the deposit_spl instruction
+    const tx = await program.methods
+        .depositSpl(new anchor.BN(depositAmount)) // Using the correct deposit amount
+        .accounts({
+          user: payer,
+          assetMint: splMint,
+          userAta: userAta, // user's token account (SPL)
+          vaultAccount: vault,
+          registry: registry,
+          vaultAta: vaultAta, // vault's token account (SPL)
+          mint: splLpMint,
+          reserve: splReserve,
+          position: position,
+          userLpAta: userLpAta,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc();
+
+    await sleep(1000);
+    console.log(`Deposit Transaction Signature: https://explorer.solana.com/tx/${tx}?cluster=devnet`);
+
+    // Fetch the balances after the deposit
+    const finalUserBalance = (await pg.connection.getTokenAccountBalance(userAta)).value.uiAmount;
+    const finalVaultBalance = (await pg.connection.getTokenAccountBalance(vaultAta)).value.uiAmount;
+
+    console.log("Final User SPL Balance:", finalUserBalance);
+    console.log("Final Vault SPL Balance:", finalVaultBalance);
 
     // Final LP Token balance
-    const postLpBalance = (
+    const postLpBalance = new anchor.BN((
       await pg.connection.getTokenAccountBalance(userLpAta)
-    ).value.uiAmount;
-    console.log("Final User LP balance: ", postLpBalance);
+    ).value.amount);
+    console.log("Final User LP balance: ", postLpBalance.toString());
 
     // Validate the changes in balances
     assert.equal(
@@ -354,19 +1232,21 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
         finalVaultBalance, initialVaultBalance + (depositAmount / (10 ** 9)),
         "Vault's balance should increase by the deposit amount."
     );
-    assert.equal(
-      initialLpBalance + mintAmount,
-      postLpBalance,
-      "Post LP balance should equal initial plus mint amount"
+    assert.ok(
+      initialLpBalance.add(expectedLp).eq(postLpBalance),
+      "Post LP balance should equal initial plus the proportional LP amount"
     );
 
+    // The reserve records the deposit
+    const reserveAfter = await program.account.assetReserve.fetch(splReserve);
+    assert.ok(reserveAfter.balance.eq(reserveBalanceBefore.addn(depositAmount)));
+    assert.ok(reserveAfter.assetMint.equals(splMint));
+
     console.log("Deposited", depositAmount / (10 ** 9), "SPL Tokens into the vault.");
   });
 
   // Test withdraw SPL method
   it("Withdraw SPL Method", async () => {
-    const splMint = new anchor.web3.PublicKey("token_address");
-    let mintAmount = 1;
     const withdrawAmount = 1 * (10 ** 9); // Assuming the SPL token has 9 decimal places
 
     // Associated Token Account for User
@@ -383,9 +1263,9 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     });
     console.log("Vault's Associated Token Account: ", vaultAta.toString());
 
-    // console.log("LP Token mint: ", mint.toString());
+    // console.log("LP Token mint: ", splLpMint.toString());
     const userLpAta = await anchor.utils.token.associatedAddress({
-      mint: mint,
+      mint: splLpMint,
       owner: payer,
     });
     console.log("User's LP Token ATA: ", userLpAta.toString());
@@ -394,12 +1274,12 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     let initialLpBalance;
     try {
       const balance = (await pg.connection.getTokenAccountBalance(userLpAta))
-      initialLpBalance = balance.value.uiAmount;
+      initialLpBalance = new anchor.BN(balance.value.amount);
     } catch {
       // Token account not yet initiated has 0 balance
-      initialLpBalance = 0;
+      initialLpBalance = new anchor.BN(0);
     }
-    console.log("Initial User LP Balance: ", initialLpBalance);
+    console.log("Initial User LP Balance: ", initialLpBalance.toString());
 
     // Fetch the initial balance of the user and vault
     let initialUserBalance = (await pg.connection.getTokenAccountBalance(userAta)).value.uiAmount;
@@ -412,18 +1292,32 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
         throw new Error("Insufficient SPL tokens in vault's account for withdrawal.");
     }
 
+    // LP tokens to burn are rounded up in favour of the vault
+    const reserveBefore = await program.account.assetReserve.fetch(splReserve);
+    const supplyBefore = new anchor.BN(
+      (await pg.connection.getTokenSupply(splLpMint)).value.amount
+    );
+    const expectedBurn = new anchor.BN(withdrawAmount)
+      .mul(supplyBefore)
+      .add(reserveBefore.balance.subn(1))
+      .div(reserveBefore.balance);
+
     // Prepare the context for the withdraw_spl instruction
     const tx = await program.methods
         .withdrawSpl(new anchor.BN(withdrawAmount)) // Using the correct withdrawal amount
         .accounts({
           user: authority,
-          authority: authority,
+          owner: authority,
+          position: position,
           vault: vault,
+          assetMint: splMint,
           userAta: userAta, // user's token account (SPL)
           vaultAta: vaultAta, // vault's token account (SPL)
-          mint: mint,
+          mint: splLpMint,
+          reserve: splReserve,
           userLpAta: userLpAta,
           tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
         })
         .rpc();
 
@@ -435,10 +1329,10 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
     const finalVaultBalance = (await pg.connection.getTokenAccountBalance(vaultAta)).value.uiAmount;
 
     // Final LP Token balance
-    const postLpBalance = (
+    const postLpBalance = new anchor.BN((
       await pg.connection.getTokenAccountBalance(userLpAta)
-    ).value.uiAmount;
-    console.log("Final User LP balance: ", postLpBalance);
+    ).value.amount);
+    console.log("Final User LP balance: ", postLpBalance.toString());
 
     console.log("Final User SPL Balance:", finalUserBalance);
     console.log("Final Vault SPL Balance:", finalVaultBalance);
@@ -452,14 +1346,1458 @@ describe("Wallet Program", () => {// Configure the client to use the local clust
         finalVaultBalance, initialVaultBalance - (withdrawAmount / (10 ** 9)),
         "Vault's balance should decrease by the withdrawal amount."
     );
-    assert.equal(
-      initialLpBalance - mintAmount,
-      postLpBalance,
-      "Post LP balance should equal initial minus mint amount"
+    assert.ok(
+      initialLpBalance.sub(expectedBurn).eq(postLpBalance),
+      "Post LP balance should equal initial minus the proportional burn amount"
     );
 
+    // The reserve records the withdrawal
+    const reserveAfter = await program.account.assetReserve.fetch(splReserve);
+    assert.ok(reserveAfter.balance.eq(reserveBefore.balance.subn(withdrawAmount)));
+
     console.log("Withdrawn", withdrawAmount / (10 ** 9), "SPL Tokens from the vault.");
   });
+
+  // Test LP amounts of an asset with fewer decimals than its LP mint
+  it("Deposit SPL scales LP amounts across decimals", async () => {
+    const [sixDecimalLpMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), vault.toBuffer(), sixDecimalMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [sixDecimalReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), vault.toBuffer(), sixDecimalMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+
+    const registryAccount = await program.account.assetRegistry.fetch(registry);
+    if (!registryAccount.assets.some((asset) => asset.mint.equals(sixDecimalMint))) {
+      await program.methods
+        .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+        .accounts({ assetManager: authority, vaultAccount: vault, registry, assetMint: sixDecimalMint, lpMint: sixDecimalLpMint })
+        .rpc();
+    }
+    if (!(await pg.connection.getAccountInfo(sixDecimalLpMint))) {
+      const [lpMetadata] = web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from(METADATA_SEED),
+          TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+          sixDecimalLpMint.toBuffer(),
+        ],
+        TOKEN_METADATA_PROGRAM_ID
+      );
+      await program.methods
+        .initToken(metadata) // 9 decimals
+        .accounts({
+          metadata: lpMetadata,
+          authority,
+          vaultAccount: vault,
+          registry,
+          assetMint: sixDecimalMint,
+          mint: sixDecimalLpMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+
+    const config = (await program.account.assetRegistry.fetch(registry)).assets
+      .find((asset) => asset.mint.equals(sixDecimalMint));
+    assert.equal(config.assetDecimals, 6);
+    assert.equal(config.lpDecimals, 9);
+
+    const userAta = await anchor.utils.token.associatedAddress({ mint: sixDecimalMint, owner: payer });
+    const vaultAta = await anchor.utils.token.associatedAddress({ mint: sixDecimalMint, owner: vault });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: sixDecimalLpMint, owner: payer });
+    const lpBalance = async () => {
+      const info = await pg.connection.getAccountInfo(userLpAta);
+      return info ? new anchor.BN((await pg.connection.getTokenAccountBalance(userLpAta)).value.amount) : new anchor.BN(0);
+    };
+
+    const amount = new anchor.BN(10 ** 6); // 1 whole token
+    const supplyBefore = new anchor.BN((await pg.connection.getTokenSupply(sixDecimalLpMint)).value.amount);
+    const reserveBefore = await program.account.assetReserve.fetchNullable(sixDecimalReserve);
+    const expectedLp = supplyBefore.isZero()
+      ? scaleAmount(amount, 6, 9)
+      : amount.mul(supplyBefore).div(reserveBefore.balance);
+    const lpBefore = await lpBalance();
+
+    await program.methods
+      .depositSpl(amount)
+      .accounts({
+        user: payer,
+        assetMint: sixDecimalMint,
+        userAta,
+        vaultAccount: vault,
+        registry,
+        vaultAta,
+        mint: sixDecimalLpMint,
+        reserve: sixDecimalReserve,
+        position,
+        userLpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+
+    // 1 whole token of 6 decimals is worth 1 whole LP token of 9 decimals
+    const minted = (await lpBalance()).sub(lpBefore);
+    assert.ok(minted.eq(expectedLp));
+    if (supplyBefore.isZero()) {
+      assert.equal(minted.toString(), (10 ** 9).toString());
+    }
+
+    // Withdrawing the same amount burns the same LP tokens
+    await program.methods
+      .withdrawSpl(amount)
+      .accounts({
+        user: authority,
+        owner: authority,
+        position,
+        vault,
+        assetMint: sixDecimalMint,
+        userAta,
+        vaultAta,
+        mint: sixDecimalLpMint,
+        reserve: sixDecimalReserve,
+        userLpAta,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    assert.ok((await lpBalance()).eq(lpBefore));
+  });
+
+  // Test deposit and withdraw of a Token-2022 asset through the token interface
+  it("Deposit and Withdraw Token-2022 Asset", async () => {
+    const registryAccount = await program.account.assetRegistry.fetch(registry);
+    if (!registryAccount.assets.some((asset) => asset.mint.equals(token2022Mint))) {
+      await program.methods
+        .addAsset({
+          enabled: true,
+          depositCap: new anchor.BN(0), // No cap
+          minDeposit: new anchor.BN(1),
+        })
+        .accounts({
+          assetManager: authority,
+          vaultAccount: vault,
+          registry,
+          assetMint: token2022Mint,
+          lpMint: token2022LpMint,
+        })
+        .rpc();
+    }
+
+    if (!(await pg.connection.getAccountInfo(token2022LpMint))) {
+      const [lpMetadata] = web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from(METADATA_SEED),
+          TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+          token2022LpMint.toBuffer(),
+        ],
+        TOKEN_METADATA_PROGRAM_ID
+      );
+      await program.methods
+        .initToken(metadata)
+        .accounts({
+          metadata: lpMetadata,
+          authority,
+          vaultAccount: vault,
+          registry,
+          assetMint: token2022Mint,
+          mint: token2022LpMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+
+    // The LP mint lives under the Token-2022 program, like its asset
+    const lpInfo = await pg.connection.getAccountInfo(token2022LpMint);
+    assert.ok(lpInfo.owner.equals(TOKEN_2022_PROGRAM_ID));
+
+    const amount = new anchor.BN(10 ** 9);
+    const userAta = token2022Address(token2022Mint, payer);
+    const vaultAta = token2022Address(token2022Mint, vault);
+    const userLpAta = token2022Address(token2022LpMint, payer);
+    const reserveBefore = await program.account.assetReserve.fetchNullable(token2022Reserve);
+    const reserveBalanceBefore = reserveBefore ? reserveBefore.balance : new anchor.BN(0);
+    const tokenAmount = async (account) => {
+      const info = await pg.connection.getAccountInfo(account);
+      return info ? new anchor.BN((await pg.connection.getTokenAccountBalance(account)).value.amount) : new anchor.BN(0);
+    };
+    const vaultAtaBefore = await tokenAmount(vaultAta);
+
+    await program.methods
+      .depositSpl(amount)
+      .accounts({
+        user: payer,
+        assetMint: token2022Mint,
+        userAta,
+        vaultAccount: vault,
+        registry,
+        vaultAta,
+        mint: token2022LpMint,
+        reserve: token2022Reserve,
+        position,
+        userLpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+
+    // Only what reached the vault is credited, which is less than `amount` if the mint charges a transfer fee
+    const received = (await tokenAmount(vaultAta)).sub(vaultAtaBefore);
+    assert.ok(received.lte(amount));
+    let reserveAccount = await program.account.assetReserve.fetch(token2022Reserve);
+    assert.ok(reserveAccount.balance.eq(reserveBalanceBefore.add(received)));
+    const userBefore = await tokenAmount(userAta);
+
+    await program.methods
+      .withdrawSpl(received)
+      .accounts({
+        user: authority,
+        owner: authority,
+        position,
+        vault,
+        assetMint: token2022Mint,
+        userAta,
+        vaultAta,
+        mint: token2022LpMint,
+        reserve: token2022Reserve,
+        userLpAta,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // The vault pays out the full amount and the recipient bears any outbound fee
+    reserveAccount = await program.account.assetReserve.fetch(token2022Reserve);
+    assert.ok(reserveAccount.balance.eq(reserveBalanceBefore));
+    assert.ok((await tokenAmount(userAta)).sub(userBefore).lte(received));
+
+    // The asset's token program must be used for its accounts
+    await expectError(
+      program.methods
+        .withdrawSpl(amount)
+        .accounts({
+          user: authority,
+          owner: authority,
+          position,
+          vault,
+          assetMint: token2022Mint,
+          userAta,
+          vaultAta,
+          mint: token2022LpMint,
+          reserve: token2022Reserve,
+          userLpAta,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc(),
+      "ConstraintTokenTokenProgram"
+    );
+  });
+
+  // LP tokens and the reserve follow what the vault received, after the transfer fee. Runs on
+  // a new vault every time so the first deposit sets the LP price.
+  it("Deposit SPL credits the amount received after a transfer fee", async () => {
+    const { vault: feeVault, registry: feeRegistry, position: feePosition } = await setUpVault(
+      new anchor.BN(Date.now())
+    );
+    const [feeLpMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), feeVault.toBuffer(), transferFeeMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [feeLpMetadata] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(METADATA_SEED), TOKEN_METADATA_PROGRAM_ID.toBuffer(), feeLpMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const [feeReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), feeVault.toBuffer(), transferFeeMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const userAta = token2022Address(transferFeeMint, payer);
+    const vaultAta = token2022Address(transferFeeMint, feeVault);
+    const userLpAta = token2022Address(feeLpMint, payer);
+
+    await program.methods
+      .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+      .accounts({
+        assetManager: authority,
+        vaultAccount: feeVault,
+        registry: feeRegistry,
+        assetMint: transferFeeMint,
+        lpMint: feeLpMint,
+      })
+      .rpc();
+    await program.methods
+      .initToken(metadata)
+      .accounts({
+        metadata: feeLpMetadata,
+        authority,
+        vaultAccount: feeVault,
+        registry: feeRegistry,
+        assetMint: transferFeeMint,
+        mint: feeLpMint,
+        payer,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .rpc();
+
+    // The fee the token program withholds from a transfer of `amount`
+    const mintInfo = (await pg.connection.getParsedAccountInfo(transferFeeMint)).value.data.parsed.info;
+    const feeConfig = mintInfo.extensions.find((extension) => extension.extension === "transferFeeConfig").state;
+    const { transferFeeBasisPoints, maximumFee } = feeConfig.newerTransferFee;
+    const amount = new anchor.BN(10).pow(new anchor.BN(mintInfo.decimals));
+    const uncappedFee = amount.muln(transferFeeBasisPoints).addn(9999).divn(10000);
+    const fee = anchor.BN.min(uncappedFee, new anchor.BN(maximumFee.toString()));
+    assert.ok(fee.gtn(0), "The mint should charge a transfer fee.");
+
+    await program.methods
+      .depositSpl(amount)
+      .accounts({
+        user: payer,
+        assetMint: transferFeeMint,
+        userAta,
+        vaultAccount: feeVault,
+        registry: feeRegistry,
+        vaultAta,
+        mint: feeLpMint,
+        reserve: feeReserve,
+        position: feePosition,
+        userLpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+
+    const received = amount.sub(fee);
+    const vaultAtaAmount = (await pg.connection.getTokenAccountBalance(vaultAta)).value.amount;
+    assert.equal(vaultAtaAmount, received.toString());
+    assert.ok((await program.account.assetReserve.fetch(feeReserve)).balance.eq(received));
+    const expectedLp = scaleAmount(received, mintInfo.decimals, metadata.decimals);
+    assert.equal((await pg.connection.getTokenAccountBalance(userLpAta)).value.amount, expectedLp.toString());
+  });
+
+  // Token accounts that are not the vault's ATA for the asset are rejected
+  it("Deposit SPL rejects spoofed vault token account", async () => {
+    const userAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: splLpMint, owner: payer });
+
+    // The user's own token account passed as the vault side
+    await expectError(
+      program.methods
+        .depositSpl(new anchor.BN(1))
+        .accounts({
+          user: payer,
+          assetMint: splMint,
+          userAta: userAta,
+          vaultAccount: vault,
+          registry: registry,
+          vaultAta: userAta,
+          mint: splLpMint,
+          reserve: splReserve,
+          position: position,
+          userLpAta: userLpAta,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc(),
+      "ConstraintTokenOwner"
+    );
+  });
+
+  it("Deposit SPL rejects mismatched mint", async () => {
+    const vaultAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: vault });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: splLpMint, owner: payer });
+
+    // A token account of another mint (the user's LP tokens) passed as the source
+    await expectError(
+      program.methods
+        .depositSpl(new anchor.BN(1))
+        .accounts({
+          user: payer,
+          assetMint: splMint,
+          userAta: userLpAta,
+          vaultAccount: vault,
+          registry: registry,
+          vaultAta: vaultAta,
+          mint: splLpMint,
+          reserve: splReserve,
+          position: position,
+          userLpAta: userLpAta,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc(),
+      "ConstraintTokenMint"
+    );
+  });
+
+  it("Withdraw SPL rejects spoofed vault token account", async () => {
+    const userAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: splLpMint, owner: payer });
+
+    await expectError(
+      program.methods
+        .withdrawSpl(new anchor.BN(1))
+        .accounts({
+          user: authority,
+          owner: authority,
+          position: position,
+          vault: vault,
+          assetMint: splMint,
+          userAta: userAta,
+          vaultAta: userAta,
+          mint: splLpMint,
+          reserve: splReserve,
+          userLpAta: userLpAta,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc(),
+      "ConstraintTokenOwner"
+    );
+  });
+
+  // Disabled assets cannot be deposited but can still be withdrawn
+  it("Deposit SPL rejects disabled asset", async () => {
+    const userAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer });
+    const vaultAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: vault });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: splLpMint, owner: payer });
+    const setEnabled = (enabled) =>
+      program.methods
+        .updateAsset({
+          enabled,
+          depositCap: new anchor.BN(0),
+          minDeposit: new anchor.BN(1),
+        })
+        .accounts({ assetManager: authority, vaultAccount: vault, registry, assetMint: splMint })
+        .rpc();
+
+    await setEnabled(false);
+
+    await expectError(
+      program.methods
+        .depositSpl(new anchor.BN(1))
+        .accounts({
+          user: payer,
+          assetMint: splMint,
+          userAta: userAta,
+          vaultAccount: vault,
+          registry: registry,
+          vaultAta: vaultAta,
+          mint: splLpMint,
+          reserve: splReserve,
+          position: position,
+          userLpAta: userLpAta,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc(),
+      "AssetDisabled"
+    );
+
+    // Withdrawals keep working while the asset is disabled
+    const reserveBefore = await program.account.assetReserve.fetch(splReserve);
+    await program.methods
+      .withdrawSpl(new anchor.BN(1))
+      .accounts({
+        user: authority,
+        owner: authority,
+        position: position,
+        vault: vault,
+        assetMint: splMint,
+        userAta: userAta,
+        vaultAta: vaultAta,
+        mint: splLpMint,
+        reserve: splReserve,
+        userLpAta: userLpAta,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const reserveAfter = await program.account.assetReserve.fetch(splReserve);
+    assert.ok(reserveAfter.balance.eq(reserveBefore.balance.subn(1)));
+
+    await setEnabled(true);
+  });
+
+  // Withdrawals only pay out to the owner or a recipient they approved
+  it("Withdraw rejects unapproved recipient", async () => {
+    const source = await anchor.utils.token.associatedAddress({ mint, owner: payer });
+    const recipient = web3.Keypair.generate().publicKey;
+    const withdrawTo = (user) =>
+      program.methods
+        .withdraw(new anchor.BN(1))
+        .accounts({
+          vaultAccount: vault,
+          user,
+          owner: authority,
+          position: position,
+          mint,
+          source,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    await expectError(withdrawTo(recipient), "UnapprovedRecipient");
+
+    // Once approved, the recipient can receive withdrawals
+    await program.methods
+      .setWithdrawRecipient(recipient)
+      .accounts({ owner: authority, position })
+      .rpc();
+    const positionAccount = await program.account.userPosition.fetch(position);
+    assert.ok(positionAccount.approvedRecipient.equals(recipient));
+
+    // Clear the approval again
+    await program.methods
+      .setWithdrawRecipient(web3.PublicKey.default)
+      .accounts({ owner: authority, position })
+      .rpc();
+    await expectError(withdrawTo(recipient), "UnapprovedRecipient");
+  });
+
+  // LP tokens received by transfer redeem without a deposit, which creates the position
+  it("Withdraw by a holder of transferred LP tokens", async () => {
+    const holder = web3.Keypair.generate();
+    const source = await anchor.utils.token.associatedAddress({ mint, owner: payer });
+    const holderLpAta = await anchor.utils.token.associatedAddress({ mint, owner: holder.publicKey });
+    const [holderPosition] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(POSITION_SEED), vault.toBuffer(), holder.publicKey.toBuffer()],
+      pg.PROGRAM_ID
+    );
+
+    // LP tokens burned for the withdrawal, rounded up
+    const amount = new anchor.BN(1000);
+    const vaultBefore = await program.account.vault.fetch(vault);
+    const supply = new anchor.BN((await pg.connection.getTokenSupply(mint)).value.amount);
+    const lpAmount = amount.mul(supply).add(vaultBefore.balance).subn(1).div(vaultBefore.balance);
+
+    // Fund the holder for the position rent, create its LP token account and transfer the LP
+    await provider.sendAndConfirm(
+      new web3.Transaction().add(
+        web3.SystemProgram.transfer({
+          fromPubkey: payer,
+          toPubkey: holder.publicKey,
+          lamports: web3.LAMPORTS_PER_SOL / 50,
+        }),
+        new web3.TransactionInstruction({
+          programId: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+          keys: [
+            { pubkey: payer, isSigner: true, isWritable: true },
+            { pubkey: holderLpAta, isSigner: false, isWritable: true },
+            { pubkey: holder.publicKey, isSigner: false, isWritable: false },
+            { pubkey: mint, isSigner: false, isWritable: false },
+            { pubkey: web3.SystemProgram.programId, isSigner: false, isWritable: false },
+            { pubkey: anchor.utils.token.TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+          ],
+          data: Buffer.from([1]), // CreateIdempotent
+        }),
+        new web3.TransactionInstruction({
+          programId: anchor.utils.token.TOKEN_PROGRAM_ID,
+          keys: [
+            { pubkey: source, isSigner: false, isWritable: true },
+            { pubkey: holderLpAta, isSigner: false, isWritable: true },
+            { pubkey: payer, isSigner: true, isWritable: false },
+          ],
+          data: Buffer.concat([Buffer.from([3]), lpAmount.toArrayLike(Buffer, "le", 8)]), // Transfer
+        })
+      )
+    );
+    assert.isNull(await pg.connection.getAccountInfo(holderPosition));
+
+    await program.methods
+      .withdraw(amount)
+      .accounts({
+        vaultAccount: vault,
+        user: holder.publicKey,
+        owner: holder.publicKey,
+        position: holderPosition,
+        mint,
+        source: holderLpAta,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([holder])
+      .rpc();
+
+    const holderPositionAccount = await program.account.userPosition.fetch(holderPosition);
+    assert.ok(holderPositionAccount.owner.equals(holder.publicKey));
+    assert.equal((await pg.connection.getTokenAccountBalance(holderLpAta)).value.amount, "0");
+    const vaultAfter = await program.account.vault.fetch(vault);
+    assert.ok(vaultBefore.balance.sub(vaultAfter.balance).eq(amount));
+  });
+
+  // LP tokens of one asset cannot redeem another asset
+  it("Withdraw rejects SPL LP Tokens", async () => {
+    const userSplLpAta = await anchor.utils.token.associatedAddress({
+      mint: splLpMint,
+      owner: payer,
+    });
+
+    await expectError(
+      program.methods
+        .withdraw(new anchor.BN(1))
+        .accounts({
+          vaultAccount: vault,
+          user: authority,
+          owner: authority,
+          position: position,
+          mint: splLpMint,
+          source: userSplLpAta,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc(),
+      "ConstraintSeeds"
+    );
+  });
+
+  // Test pausing single operations through the guardian
+  it("Pause blocks paused operations only", async () => {
+    const guardian = web3.Keypair.generate();
+    const outsider = web3.Keypair.generate();
+    const unpaused = {
+      deposits: false,
+      solWithdrawals: false,
+      splWithdrawals: false,
+      lpMintCreation: false,
+    };
+    const setPause = (signer, paused) =>
+      program.methods
+        .setPause(paused)
+        .accounts({ signer: signer.publicKey, vaultAccount: vault })
+        .signers([signer])
+        .rpc();
+
+    await program.methods
+      .setRole({ guardian: {} }, guardian.publicKey)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+
+    // Only the authority and the guardian can pause
+    await expectError(setPause(outsider, { ...unpaused, deposits: true }), "UnauthorizedPauser");
+
+    await setPause(guardian, { ...unpaused, deposits: true, splWithdrawals: true });
+    const vaultAccount = await program.account.vault.fetch(vault);
+    assert.ok(vaultAccount.paused.deposits);
+    assert.ok(!vaultAccount.paused.solWithdrawals);
+
+    const destination = await anchor.utils.token.associatedAddress({ mint, owner: payer });
+    await expectError(
+      program.methods
+        .deposit(new anchor.BN(1000))
+        .accounts({
+          user: authority,
+          vaultAccount: vault,
+          mint,
+          destination,
+          position,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc(),
+      "DepositsPaused"
+    );
+    await expectError(
+      program.methods
+        .withdrawSpl(new anchor.BN(1))
+        .accounts({
+          user: authority,
+          owner: authority,
+          position,
+          vault,
+          assetMint: splMint,
+          userAta: await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer }),
+          vaultAta: await anchor.utils.token.associatedAddress({ mint: splMint, owner: vault }),
+          mint: splLpMint,
+          reserve: splReserve,
+          userLpAta: await anchor.utils.token.associatedAddress({ mint: splLpMint, owner: payer }),
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc(),
+      "SplWithdrawalsPaused"
+    );
+
+    // SOL withdrawals are still open
+    await program.methods
+      .withdraw(new anchor.BN(1000))
+      .accounts({
+        vaultAccount: vault,
+        user: authority,
+        owner: authority,
+        position,
+        mint,
+        source: destination,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // The guardian resumes, and the authority clears the guardian
+    await setPause(guardian, unpaused);
+    await program.methods
+      .setRole({ guardian: {} }, web3.PublicKey.default)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+    await expectError(setPause(guardian, { ...unpaused, deposits: true }), "UnauthorizedPauser");
+  });
+
+  // Test the SOL outflow limit and a temporary raise by the guardian
+  it("Outflow limit caps withdrawals within a window", async () => {
+    const guardian = web3.Keypair.generate();
+    const source = await anchor.utils.token.associatedAddress({ mint, owner: payer });
+    const withdraw = (amount) =>
+      program.methods
+        .withdraw(new anchor.BN(amount))
+        .accounts({
+          vaultAccount: vault,
+          user: authority,
+          owner: authority,
+          position,
+          mint,
+          source,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    const setLimit = (window, limit) =>
+      program.methods
+        .setOutflowLimit(new anchor.BN(window), new anchor.BN(limit))
+        .accounts({ authority, vaultAccount: vault, reserve: null })
+        .rpc();
+    const raise = (signer, limit, duration) =>
+      program.methods
+        .raiseOutflowLimit(new anchor.BN(limit), new anchor.BN(duration))
+        .accounts({ signer: signer.publicKey, vaultAccount: vault, reserve: null })
+        .signers([signer])
+        .rpc();
+
+    const recordedOutflow = (vaultAccount) =>
+      vaultAccount.solOutflow.outflow.reduce((total, amount) => total + amount.toNumber(), 0);
+
+    await expectError(setLimit(0, 1500), "InvalidOutflowLimit");
+    await setLimit(3600, 1500);
+
+    await withdraw(1000);
+    await expectError(withdraw(1000), "OutflowLimitExceeded");
+    assert.equal(recordedOutflow(await program.account.vault.fetch(vault)), 1000);
+
+    // Only the authority and the guardian can raise the limit, and only for one window
+    await expectError(raise(guardian, 3000, 60), "UnauthorizedLimitRaise");
+    await program.methods
+      .setRole({ guardian: {} }, guardian.publicKey)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+    await expectError(raise(guardian, 3000, 7200), "InvalidOutflowLimit");
+    await raise(guardian, 3000, 60);
+    await withdraw(1000);
+
+    // Remove the limit and the guardian again
+    await setLimit(0, 0);
+    await program.methods
+      .setRole({ guardian: {} }, web3.PublicKey.default)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+    assert.equal(recordedOutflow(await program.account.vault.fetch(vault)), 0);
+
+    // A withdrawal keeps counting for a full window, so half a window after taking the whole
+    // limit nothing more can leave. Twice the limit never fits in one window.
+    await setLimit(6, 1000);
+    await withdraw(1000);
+    await sleep(3500);
+    await expectError(withdraw(500), "OutflowLimitExceeded");
+    await setLimit(0, 0);
+  });
+
+  // Test exit mode on a new vault every run, since exit mode cannot be turned off and
+  // the redemption needs fresh deposits
+  it("Exit mode blocks deposits and redeems LP tokens", async () => {
+    const {
+      vault: exitVault,
+      registry: exitRegistry,
+      mint: exitMint,
+      position: exitPosition,
+    } = await setUpVault(new anchor.BN(Date.now()), metadata);
+    const [exitSplLpMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), exitVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [exitSplLpMetadata] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(METADATA_SEED), TOKEN_METADATA_PROGRAM_ID.toBuffer(), exitSplLpMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const [exitReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), exitVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const destination = await anchor.utils.token.associatedAddress({ mint: exitMint, owner: payer });
+    const userAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer });
+    const vaultAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: exitVault });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: exitSplLpMint, owner: payer });
+    const deposit = () =>
+      program.methods
+        .deposit(new anchor.BN(web3.LAMPORTS_PER_SOL / 100))
+        .accounts({
+          user: authority,
+          vaultAccount: exitVault,
+          mint: exitMint,
+          destination,
+          position: exitPosition,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc();
+
+    // Hold SOL and an SPL asset before exit mode starts
+    await program.methods
+      .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+      .accounts({
+        assetManager: authority,
+        vaultAccount: exitVault,
+        registry: exitRegistry,
+        assetMint: splMint,
+        lpMint: exitSplLpMint,
+      })
+      .rpc();
+    await program.methods
+      .initToken(metadata)
+      .accounts({
+        metadata: exitSplLpMetadata,
+        authority,
+        vaultAccount: exitVault,
+        registry: exitRegistry,
+        assetMint: splMint,
+        mint: exitSplLpMint,
+        payer,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .rpc();
+    await deposit();
+    await program.methods
+      .depositSpl(new anchor.BN(1000))
+      .accounts({
+        user: payer,
+        assetMint: splMint,
+        userAta,
+        vaultAccount: exitVault,
+        registry: exitRegistry,
+        vaultAta,
+        mint: exitSplLpMint,
+        reserve: exitReserve,
+        position: exitPosition,
+        userLpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+
+    await program.methods
+      .enableExitMode()
+      .accounts({ authority, vaultAccount: exitVault })
+      .rpc();
+    await expectError(deposit(), "ExitModeActive");
+
+    const redeem = (remainingAccounts) =>
+      program.methods
+        .exitRedeem()
+        .accounts({
+          user: authority,
+          vault: exitVault,
+          owner: authority,
+          position: exitPosition,
+          systemProgram: web3.SystemProgram.programId,
+          mint: exitMint,
+          source: destination,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(remainingAccounts)
+        .rpc();
+    const splAccounts = (reserve) =>
+      [splMint, exitSplLpMint, reserve, vaultAta, userAta, userLpAta, anchor.utils.token.TOKEN_PROGRAM_ID].map(
+        (pubkey, index) => ({ pubkey, isWritable: index > 0 && index < 6, isSigner: false })
+      );
+    const tokenAmount = async (account) =>
+      new anchor.BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+    const supply = async (lpMint) => new anchor.BN((await pg.connection.getTokenSupply(lpMint)).value.amount);
+
+    await expectError(
+      redeem([{ pubkey: splMint, isWritable: false, isSigner: false }]),
+      "MissingExitAccounts"
+    );
+    // The reserve of another vault is rejected
+    await expectError(redeem(splAccounts(splReserve)), "ExitAccountMismatch");
+
+    // The only LP holder gets the whole recorded SOL balance and SPL reserve in one call
+    const vaultBefore = await program.account.vault.fetch(exitVault);
+    const reserveBefore = await program.account.assetReserve.fetch(exitReserve);
+    const solSupplyBefore = await supply(exitMint);
+    const splSupplyBefore = await supply(exitSplLpMint);
+    const solLpBefore = await tokenAmount(destination);
+    const splLpBefore = await tokenAmount(userLpAta);
+    const userAtaBefore = await tokenAmount(userAta);
+    await redeem(splAccounts(exitReserve));
+
+    const solPayout = solLpBefore.mul(vaultBefore.balance).div(solSupplyBefore);
+    const splPayout = splLpBefore.mul(reserveBefore.balance).div(splSupplyBefore);
+    const vaultAfter = await program.account.vault.fetch(exitVault);
+    const reserveAfter = await program.account.assetReserve.fetch(exitReserve);
+    assert.ok(vaultBefore.balance.sub(vaultAfter.balance).eq(solPayout));
+    assert.ok(reserveBefore.balance.sub(reserveAfter.balance).eq(splPayout));
+    assert.ok((await tokenAmount(userAta)).sub(userAtaBefore).eq(splPayout));
+
+    // Both LP balances are burned
+    assert.ok((await tokenAmount(destination)).isZero());
+    assert.ok((await tokenAmount(userLpAta)).isZero());
+    assert.ok(solSupplyBefore.sub(await supply(exitMint)).eq(solLpBefore));
+    assert.ok(splSupplyBefore.sub(await supply(exitSplLpMint)).eq(splLpBefore));
+  });
+
+  // Test closing a vault of its own, which is created again on every run
+  it("Close Vault returns the rent to the authority", async () => {
+    const {
+      vault: closedVault,
+      registry: closedRegistry,
+      position: closedPosition,
+    } = await setUpVault(new anchor.BN(4));
+    const [closedReserve] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(RESERVE_SEED), closedVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const [closedSplLpMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from(MINT_SEED), closedVault.toBuffer(), splMint.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const userAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: payer });
+    const vaultAta = await anchor.utils.token.associatedAddress({ mint: splMint, owner: closedVault });
+    const userLpAta = await anchor.utils.token.associatedAddress({ mint: closedSplLpMint, owner: payer });
+    const assetAccounts = {
+      assetManager: authority,
+      vaultAccount: closedVault,
+      registry: closedRegistry,
+      assetMint: splMint,
+    };
+    const reserveAccounts = [
+      closedReserve,
+      closedSplLpMint,
+      vaultAta,
+      anchor.utils.token.TOKEN_PROGRAM_ID,
+    ].map((pubkey, index) => ({ pubkey, isWritable: index !== 3, isSigner: false }));
+    const close = (
+      signer,
+      remainingAccounts = reserveAccounts,
+      vaultAccount = closedVault,
+      registry = closedRegistry
+    ) =>
+      program.methods
+        .closeVault()
+        .accounts({
+          authority: signer.publicKey,
+          vaultAccount,
+          registry,
+          mint: null,
+          multisig: null,
+          timelock: null,
+        })
+        .remainingAccounts(remainingAccounts)
+        .signers([signer])
+        .rpc();
+
+    // SOL left in a vault belongs to its LP holders, so it cannot be closed. A new vault on
+    // every run, since it keeps the SOL.
+    const { vault: fundedVault, registry: fundedRegistry } = await setUpVault(new anchor.BN(Date.now()));
+    await provider.sendAndConfirm(
+      new web3.Transaction().add(
+        web3.SystemProgram.transfer({ fromPubkey: payer, toPubkey: fundedVault, lamports: 1000 })
+      )
+    );
+    await program.methods
+      .syncBalance()
+      .accounts({ signer: authority, vaultAccount: fundedVault, treasury: null })
+      .rpc();
+    await expectError(close(pg.wallet.keypair, [], fundedVault, fundedRegistry), "VaultNotEmpty");
+
+    // Open a reserve with a deposit, and empty it again
+    await program.methods
+      .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+      .accounts({ ...assetAccounts, lpMint: closedSplLpMint })
+      .rpc();
+    if (!(await pg.connection.getAccountInfo(closedSplLpMint))) {
+      const [lpMetadata] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from(METADATA_SEED), TOKEN_METADATA_PROGRAM_ID.toBuffer(), closedSplLpMint.toBuffer()],
+        TOKEN_METADATA_PROGRAM_ID
+      );
+      await program.methods
+        .initToken(metadata)
+        .accounts({
+          metadata: lpMetadata,
+          authority,
+          vaultAccount: closedVault,
+          registry: closedRegistry,
+          assetMint: splMint,
+          mint: closedSplLpMint,
+          payer,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .rpc();
+    }
+    const amount = new anchor.BN(1000);
+    await program.methods
+      .depositSpl(amount)
+      .accounts({
+        user: payer,
+        assetMint: splMint,
+        userAta,
+        vaultAccount: closedVault,
+        registry: closedRegistry,
+        vaultAta,
+        mint: closedSplLpMint,
+        reserve: closedReserve,
+        position: closedPosition,
+        userLpAta,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+      })
+      .rpc();
+    assert.equal((await program.account.vault.fetch(closedVault)).reserveCount, 1);
+
+    // Registered assets must be removed first
+    await expectError(close(pg.wallet.keypair), "AssetsStillRegistered");
+    await program.methods
+      .withdrawSpl(amount)
+      .accounts({
+        user: authority,
+        owner: authority,
+        position: closedPosition,
+        vault: closedVault,
+        assetMint: splMint,
+        userAta,
+        vaultAta,
+        mint: closedSplLpMint,
+        reserve: closedReserve,
+        userLpAta,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .removeAsset()
+      .accounts({ ...assetAccounts, reserve: closedReserve })
+      .rpc();
+
+    // Every reserve must be closed with the vault
+    await expectError(close(pg.wallet.keypair, []), "ReservesStillOpen");
+    await expectError(close(web3.Keypair.generate()), "ConstraintHasOne");
+
+
+    let rent = 0;
+    for (const address of [closedVault, closedRegistry, closedReserve, vaultAta]) {
+      rent += (await pg.connection.getAccountInfo(address)).lamports;
+    }
+    const balanceBefore = await pg.connection.getBalance(authority);
+    await close(pg.wallet.keypair);
+
+    for (const address of [closedVault, closedRegistry, closedReserve, vaultAta]) {
+      assert.isNull(await pg.connection.getAccountInfo(address));
+    }
+    // The authority gets the rent back, less the transaction fee
+    assert.ok((await pg.connection.getBalance(authority)) > balanceBefore + rent - 10000);
+  });
+
+  // Test compliance freezes on a vault of its own with a freezable SOL LP mint
+  it("Compliance freezes and thaws LP token accounts", async () => {
+    const {
+      vault: frozenVault,
+      mint: frozenMint,
+      position: frozenPosition,
+    } = await setUpVault(new anchor.BN(5), { ...metadata, freezable: true });
+    const lpAccount = await anchor.utils.token.associatedAddress({ mint: frozenMint, owner: payer });
+    const amount = new anchor.BN(web3.LAMPORTS_PER_SOL / 100);
+
+    const deposit = () =>
+      program.methods
+        .deposit(amount)
+        .accounts({
+          user: authority,
+          vaultAccount: frozenVault,
+          mint: frozenMint,
+          destination: lpAccount,
+          position: frozenPosition,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: anchor.utils.token.ASSOCIATED_PROGRAM_ID,
+        })
+        .rpc();
+    const withdraw = () =>
+      program.methods
+        .withdraw(amount)
+        .accounts({
+          vaultAccount: frozenVault,
+          user: authority,
+          owner: authority,
+          position: frozenPosition,
+          mint: frozenMint,
+          source: lpAccount,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    const setFrozen = (frozen, signer, vaultAccount = frozenVault, lpMint = frozenMint, account = lpAccount) =>
+      (frozen ? program.methods.freezeLpAccount() : program.methods.thawLpAccount())
+        .accounts({
+          compliance: signer.publicKey,
+          vaultAccount,
+          assetMint: NATIVE_MINT,
+          mint: lpMint,
+          lpAccount: account,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        })
+        .signers([signer])
+        .rpc();
+
+    await deposit();
+
+    // Only the compliance role can freeze
+    const compliance = web3.Keypair.generate();
+    await expectError(setFrozen(true, compliance), "ConstraintHasOne");
+    await program.methods
+      .setRole({ compliance: {} }, compliance.publicKey)
+      .accounts({ authority, vaultAccount: frozenVault })
+      .rpc();
+
+    await setFrozen(true, compliance);
+    await expectError(deposit(), "LpAccountFrozen");
+    await expectError(withdraw(), "LpAccountFrozen");
+
+    await setFrozen(false, compliance);
+    await withdraw();
+
+    // LP mints created without `freezable` cannot freeze
+    await program.methods
+      .setRole({ compliance: {} }, compliance.publicKey)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+    await expectError(
+      setFrozen(true, compliance, vault, mint, await anchor.utils.token.associatedAddress({ mint, owner: payer })),
+      "LpMintNotFreezable"
+    );
+    await program.methods
+      .setRole({ compliance: {} }, web3.PublicKey.default)
+      .accounts({ authority, vaultAccount: vault })
+      .rpc();
+  });
+
+  // Test the two-step authority transfer and role checks, on the second vault
+  it("Authority transfer and roles", async () => {
+    const newAuthority = web3.Keypair.generate();
+    const assetManager = web3.Keypair.generate();
+    const fund = new web3.Transaction().add(
+      web3.SystemProgram.transfer({
+        fromPubkey: payer,
+        toPubkey: newAuthority.publicKey,
+        lamports: web3.LAMPORTS_PER_SOL / 100,
+      })
+    );
+    await provider.sendAndConfirm(fund);
+
+    // The creator holds every role of a new vault
+    let vaultAccount = await program.account.vault.fetch(secondVault);
+    assert.ok(vaultAccount.feeManager.equals(authority));
+    assert.ok(vaultAccount.assetManager.equals(authority));
+
+    // Hand the asset manager role to another key
+    await program.methods
+      .setRole({ assetManager: {} }, assetManager.publicKey)
+      .accounts({ authority, vaultAccount: secondVault })
+      .rpc();
+    await expectError(
+      program.methods
+        .addAsset({ enabled: true, depositCap: new anchor.BN(0), minDeposit: new anchor.BN(1) })
+        .accounts({
+          assetManager: authority,
+          vaultAccount: secondVault,
+          registry: secondRegistry,
+          assetMint: splMint,
+          lpMint: web3.PublicKey.findProgramAddressSync(
+            [Buffer.from(MINT_SEED), secondVault.toBuffer(), splMint.toBuffer()],
+            pg.PROGRAM_ID
+          )[0],
+        })
+        .rpc(),
+      "ConstraintHasOne"
+    );
+
+    // Only the proposed key can accept the authority
+    await program.methods
+      .proposeAuthority(newAuthority.publicKey)
+      .accounts({ authority, vaultAccount: secondVault })
+      .rpc();
+    await expectError(
+      program.methods
+        .acceptAuthority()
+        .accounts({ newAuthority: assetManager.publicKey, vaultAccount: secondVault })
+        .signers([assetManager])
+        .rpc(),
+      "NotPendingAuthority"
+    );
+    await program.methods
+      .acceptAuthority()
+      .accounts({ newAuthority: newAuthority.publicKey, vaultAccount: secondVault })
+      .signers([newAuthority])
+      .rpc();
+
+    vaultAccount = await program.account.vault.fetch(secondVault);
+    assert.ok(vaultAccount.authority.equals(newAuthority.publicKey));
+    assert.ok(vaultAccount.pendingAuthority.equals(web3.PublicKey.default));
+    assert.ok(vaultAccount.creator.equals(authority)); // The vault address does not change
+    // The fee and asset manager roles move to the new authority
+    assert.ok(vaultAccount.feeManager.equals(newAuthority.publicKey));
+    assert.ok(vaultAccount.assetManager.equals(newAuthority.publicKey));
+
+    // Hand the authority back so later runs start from the same state. This also returns the
+    // manager roles.
+    await program.methods
+      .proposeAuthority(authority)
+      .accounts({ authority: newAuthority.publicKey, vaultAccount: secondVault })
+      .signers([newAuthority])
+      .rpc();
+    await program.methods
+      .acceptAuthority()
+      .accounts({ newAuthority: authority, vaultAccount: secondVault })
+      .rpc();
+    vaultAccount = await program.account.vault.fetch(secondVault);
+    assert.ok(vaultAccount.feeManager.equals(authority));
+    assert.ok(vaultAccount.assetManager.equals(authority));
+  });
+
+  // Test a 2-of-2 multisig pausing the second vault as its guardian
+  it("Multisig proposals need the threshold of approvals", async () => {
+    // Same co-signer on every run, so an existing multisig can be reused
+    const cosigner = web3.Keypair.fromSeed(new Uint8Array(32).fill(7));
+    const [multisig] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("multisig"), secondVault.toBuffer()],
+      pg.PROGRAM_ID
+    );
+
+    if (!(await pg.connection.getAccountInfo(multisig))) {
+      await expectError(
+        program.methods
+          .createMultisig([authority, cosigner.publicKey], 3)
+          .accounts({ authority, vaultAccount: secondVault, multisig })
+          .rpc(),
+        "InvalidThreshold"
+      );
+      await program.methods
+        .createMultisig([authority, cosigner.publicKey], 2)
+        .accounts({ authority, vaultAccount: secondVault, multisig })
+        .rpc();
+    }
+
+    // Hand the guardian role to the multisig
+    await program.methods
+      .setRole({ guardian: {} }, multisig)
+      .accounts({ authority, vaultAccount: secondVault })
+      .rpc();
+
+    const propose = async (action) => {
+      const { proposalCount } = await program.account.multisig.fetch(multisig);
+      const [proposal] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("proposal"), multisig.toBuffer(), proposalCount.toArrayLike(Buffer, "le", 8)],
+        pg.PROGRAM_ID
+      );
+      await program.methods
+        .createProposal(action)
+        .accounts({ proposer: authority, multisig, proposal })
+        .rpc();
+      return proposal;
+    };
+    const execute = (proposal) =>
+      program.methods
+        .executeProposal()
+        .accounts({
+          multisig,
+          proposal,
+          vaultAccount: secondVault,
+          registry: null,
+          assetMint: null,
+          lpMint: null,
+          reserve: null,
+          timelock: null,
+        })
+        .rpc();
+    const approve = (proposal) =>
+      program.methods
+        .approveProposal()
+        .accounts({ signer: cosigner.publicKey, multisig, proposal })
+        .signers([cosigner])
+        .rpc();
+    const unpaused = {
+      deposits: false,
+      solWithdrawals: false,
+      splWithdrawals: false,
+      lpMintCreation: false,
+    };
+
+    // One approval of two is not enough
+    const pause = await propose({ setPause: { paused: { ...unpaused, deposits: true } } });
+    await expectError(execute(pause), "NotEnoughApprovals");
+
+    await approve(pause);
+    await expectError(approve(pause), "AlreadyApproved");
+    await execute(pause);
+    assert.ok((await program.account.vault.fetch(secondVault)).paused.deposits);
+    await expectError(execute(pause), "ProposalAlreadyExecuted");
+
+    // The multisig only holds the guardian role
+    const fees = await propose({
+      applyChange: { change: { setSurplusPolicy: { policy: { yield: {} }, treasury: web3.PublicKey.default } } },
+    });
+    await approve(fees);
+    await expectError(execute(fees), "MultisigNotAuthorized");
+
+    // It can never take the authority
+    await expectError(
+      program.methods
+        .proposeAuthority(multisig)
+        .accounts({ authority, vaultAccount: secondVault })
+        .rpc(),
+      "MultisigCannotBeAuthority"
+    );
+
+    const resume = await propose({ setPause: { paused: unpaused } });
+    await approve(resume);
+    await execute(resume);
+    assert.ok(!(await program.account.vault.fetch(secondVault)).paused.deposits);
+
+    await program.methods
+      .setRole({ guardian: {} }, web3.PublicKey.default)
+      .accounts({ authority, vaultAccount: secondVault })
+      .rpc();
+  });
+
+  // Test the timelock on a vault of its own, since it cannot be turned off
+  it("Timelock delays queued changes", async () => {
+    const { vault: timelockVault, registry: timelockRegistry } = await setUpVault(new anchor.BN(2));
+    const [timelock] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("timelock"), timelockVault.toBuffer()],
+      pg.PROGRAM_ID
+    );
+    const delay = 5;
+
+    if (!(await provider.connection.getAccountInfo(timelock))) {
+      await expectError(
+        program.methods
+          .initTimelock(new anchor.BN(0))
+          .accounts({ authority, vaultAccount: timelockVault, timelock })
+          .rpc(),
+        "InvalidTimelockDelay"
+      );
+      await program.methods
+        .initTimelock(new anchor.BN(delay))
+        .accounts({ authority, vaultAccount: timelockVault, timelock })
+        .rpc();
+    }
+
+    // Direct changes are closed once the timelock is on
+    await expectError(
+      program.methods
+        .setSurplusPolicy({ yield: {} }, web3.PublicKey.default)
+        .accounts({ feeManager: authority, vaultAccount: timelockVault })
+        .rpc(),
+      "TimelockRequired"
+    );
+
+    const stranger = web3.Keypair.generate();
+    const change = { setSurplusPolicy: { policy: { yield: {} }, treasury: web3.PublicKey.default } };
+    await expectError(
+      program.methods
+        .queueChange(change)
+        .accounts({ signer: stranger.publicKey, vaultAccount: timelockVault, timelock })
+        .signers([stranger])
+        .rpc(),
+      "UnauthorizedChange"
+    );
+
+    const queue = async () => {
+      const { nextId } = await program.account.timelock.fetch(timelock);
+      await program.methods
+        .queueChange(change)
+        .accounts({ signer: authority, vaultAccount: timelockVault, timelock })
+        .rpc();
+      return nextId;
+    };
+    const execute = (id) =>
+      program.methods
+        .executeChange(id)
+        .accounts({
+          vaultAccount: timelockVault,
+          timelock,
+          registry: null,
+          assetMint: null,
+          lpMint: null,
+          reserve: null,
+        })
+        .rpc();
+
+    // A queued change waits for the delay and can be cancelled meanwhile
+    const cancelled = await queue();
+    const queued = (await program.account.timelock.fetch(timelock)).changes.find((c) => c.id.eq(cancelled));
+    assert.equal(queued.eta.sub(queued.queuedAt).toNumber(), delay);
+    await expectError(execute(cancelled), "TimelockNotExpired");
+    await program.methods
+      .cancelChange(cancelled)
+      .accounts({ authority, vaultAccount: timelockVault, timelock })
+      .rpc();
+    await expectError(execute(cancelled), "ChangeNotQueued");
+
+    const id = await queue();
+    await new Promise((resolve) => setTimeout(resolve, (delay + 2) * 1000));
+    await execute(id);
+
+    const timelockAccount = await program.account.timelock.fetch(timelock);
+    assert.ok(!timelockAccount.changes.some((c) => c.id.eq(id)));
+    assert.deepEqual((await program.account.vault.fetch(timelockVault)).surplusPolicy, { yield: {} });
+  });
 });
 
 
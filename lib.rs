@@ -1,23 +1,43 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_lang::system_program;
 use anchor_spl::{
-    associated_token::AssociatedToken,
-    token::{
-        self, 
+    associated_token::{get_associated_token_address_with_program_id, AssociatedToken},
+    token::spl_token::native_mint,
+    token_interface::{
         mint_to, 
+        sync_native,
+        freeze_account,
+        thaw_account,
+        set_authority,
+        close_account,
+        transfer_checked,
         Mint, 
         MintTo, 
-        Token, 
         TokenAccount, 
+        TokenInterface,
         burn, 
         Burn, 
-        Transfer as SplTransfer
+        SyncNative,
+        CloseAccount,
+        TransferChecked,
+        FreezeAccount,
+        ThawAccount,
+        SetAuthority,
+        spl_token_2022::instruction::AuthorityType,
     },
     metadata::{
         create_metadata_accounts_v3,
-        mpl_token_metadata::types::DataV2,
+        update_metadata_accounts_v2,
+        verify_collection,
+        verify_sized_collection_item,
+        mpl_token_metadata::types::{Collection, Creator, DataV2},
         CreateMetadataAccountsV3,
+        UpdateMetadataAccountsV2,
+        VerifyCollection,
+        VerifySizedCollectionItem,
         Metadata as Metaplex,
+        MetadataAccount,
     },
 };
 
@@ -29,28 +49,159 @@ declare_id!("program_id");
 pub mod wallet {
     use super::*;
 
-    // Initialize the Vault
-    pub fn init_vault(ctx: Context<Initialize>) -> Result<()> {
+    // Initialize a Vault, identified by its creator and `vault_id`
+    pub fn init_vault(ctx: Context<Initialize>, vault_id: u64) -> Result<()> {
         // Set the authority to the signer of the transaction
+        ctx.accounts.vault_account.version = ACCOUNT_VERSION;
         ctx.accounts.vault_account.authority = *ctx.accounts.authority.key;
         ctx.accounts.vault_account.balance = 0;
+        ctx.accounts.vault_account.creator = *ctx.accounts.authority.key;
+        ctx.accounts.vault_account.vault_id = vault_id;
+        ctx.accounts.vault_account.bump = ctx.bumps.vault_account;
 
-        msg!("Vault initialized successfully.");
+        // The creator holds every role until it hands them out with `set_role`
+        ctx.accounts.vault_account.fee_manager = *ctx.accounts.authority.key;
+        ctx.accounts.vault_account.asset_manager = *ctx.accounts.authority.key;
+
+        // Start with an empty registry of accepted SPL mints
+        ctx.accounts.registry.version = ACCOUNT_VERSION;
+        ctx.accounts.registry.vault = ctx.accounts.vault_account.key();
+        ctx.accounts.registry.bump = ctx.bumps.registry;
+        ctx.accounts.registry.assets = Vec::new();
+
+        msg!("Vault {} initialized successfully.", vault_id);
+        Ok(())
+    }
+
+    // Upgrade the single vault of the original program, derived from `[b"myvault"]` alone, to
+    // the current layout in place. Its 48 bytes of authority and balance are version 1. The
+    // authority pays the rent of the added space.
+    //
+    // The vault keeps its address, SOL and LP mint `[b"mint"]`. No per-creator instruction can
+    // address it, so it starts in exit mode and its LP holders redeem with `redeem_legacy`.
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        let vault = &ctx.accounts.vault_account;
+        if *vault.owner != crate::ID {
+            return Err(Errors::InvalidMigrationAccount.into());
+        }
+        // The original LP mint is only there once `init_token` was called
+        let (lp_mint, lp_decimals) = if ctx.accounts.lp_mint.data_is_empty() {
+            (Pubkey::default(), 0)
+        } else {
+            let mint = Mint::try_deserialize(&mut &ctx.accounts.lp_mint.try_borrow_data()?[..])?;
+            (ctx.accounts.lp_mint.key(), mint.decimals)
+        };
+        let upgraded = Vault::from_legacy(
+            &vault.try_borrow_data()?,
+            ctx.bumps.vault_account,
+            lp_mint,
+            lp_decimals,
+        )?;
+        if upgraded.authority != ctx.accounts.authority.key() {
+            return Err(Errors::InvalidMigrationAccount.into());
+        }
+
+        // Lamports above the rent-exempt minimum belong to depositors, so the whole rent of the
+        // added space comes from the authority
+        let rent = Rent::get()?;
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.authority.to_account_info(),
+                    to: vault.to_account_info(),
+                },
+            ),
+            rent.minimum_balance(Vault::SIZE) - rent.minimum_balance(Vault::LEGACY_SIZE),
+        )?;
+        vault.realloc(Vault::SIZE, true)?;
+        upgraded.try_serialize(&mut &mut vault.try_borrow_mut_data()?[..])?;
+
+        msg!("Original vault migrated to version {}.", ACCOUNT_VERSION);
+        Ok(())
+    }
+
+    // Burn LP tokens of the original vault for its SOL, 1 lamport per LP token as they were
+    // minted. The vault must have been migrated with `migrate_vault` first.
+    pub fn redeem_legacy(ctx: Context<RedeemLegacy>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault_account.balance < amount {
+            return Err(Errors::InsufficientBalance.into());
+        }
+
+        burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.mint.to_account_info(),
+                    from: ctx.accounts.source.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let vault_account = &mut ctx.accounts.vault_account.to_account_info();
+        let owner = &mut ctx.accounts.owner.to_account_info();
+        let post_from = vault_account
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        let post_to = owner
+            .lamports()
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        **vault_account.try_borrow_mut_lamports()? = post_from;
+        **owner.try_borrow_mut_lamports()? = post_to;
+        ctx.accounts.vault_account.balance -= amount;
+
+        msg!("Redeemed {} lamports of the original vault for {} LP tokens.", amount, amount);
         Ok(())
     }
 
-    // Initialize the SPL Token
+    // Initialize the LP Token of a vault for a deposited asset (the native mint for SOL)
     pub fn init_token(ctx: Context<InitToken>, metadata: InitTokenParams) -> Result<()> {
-        let seeds = &["mint".as_bytes(), &[ctx.bumps.mint]];
+        if ctx.accounts.vault_account.paused.lp_mint_creation {
+            return Err(Errors::LpMintCreationPaused.into());
+        }
+        metadata.validate()?;
+
+        let vault = ctx.accounts.vault_account.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+
+        // Bind the LP mint to the vault: SOL on the vault itself, SPL assets on their registry entry
+        if asset_mint == native_mint::ID {
+            ctx.accounts.vault_account.lp_mint = ctx.accounts.mint.key();
+            ctx.accounts.vault_account.lp_decimals = metadata.decimals;
+        } else {
+            let config = ctx.accounts.registry
+                .find_mut(&asset_mint)
+                .ok_or(Errors::AssetNotRegistered)?;
+            config.lp_mint = ctx.accounts.mint.key();
+            config.lp_decimals = metadata.decimals;
+        }
+
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
         let signer = [&seeds[..]];
 
+        // Creators verify themselves later through the token metadata program
+        let creators = metadata.creators.map(|creators| {
+            creators
+                .into_iter()
+                .map(|creator| Creator {
+                    address: creator.address,
+                    verified: false,
+                    share: creator.share,
+                })
+                .collect()
+        });
+
         let token_data: DataV2 = DataV2 {
             name: metadata.name,
             symbol: metadata.symbol,
             uri: metadata.uri,
-            seller_fee_basis_points: 0,
-            creators: None,
-            collection: None,
+            seller_fee_basis_points: metadata.seller_fee_basis_points,
+            creators,
+            collection: metadata.collection.map(|key| Collection { verified: false, key }),
             uses: None,
         };
 
@@ -76,13 +227,144 @@ pub mod wallet {
             None,
         )?;
 
+        // Verify the LP token as a member of its collection, signed by the collection authority
+        if let Some(collection) = metadata.collection {
+            let accounts = &ctx.accounts;
+            let (collection_authority, collection_mint, collection_metadata, collection_master_edition) = match (
+                &accounts.collection_authority,
+                &accounts.collection_mint,
+                &accounts.collection_metadata,
+                &accounts.collection_master_edition,
+            ) {
+                (Some(authority), Some(mint), Some(metadata), Some(master_edition)) => {
+                    (authority, mint, metadata, master_edition)
+                }
+                _ => return Err(Errors::MissingCollectionAccounts.into()),
+            };
+            if collection_mint.key() != collection || collection_metadata.mint != collection {
+                return Err(Errors::CollectionMismatch.into());
+            }
+
+            // Sized collections track their item count and need the sized variant
+            if collection_metadata.collection_details.is_some() {
+                verify_sized_collection_item(
+                    CpiContext::new(
+                        accounts.token_metadata_program.to_account_info(),
+                        VerifySizedCollectionItem {
+                            payer: accounts.payer.to_account_info(),
+                            metadata: accounts.metadata.to_account_info(),
+                            collection_authority: collection_authority.to_account_info(),
+                            collection_mint: collection_mint.to_account_info(),
+                            collection_metadata: collection_metadata.to_account_info(),
+                            collection_master_edition: collection_master_edition.to_account_info(),
+                        },
+                    ),
+                    None,
+                )?;
+            } else {
+                verify_collection(
+                    CpiContext::new(
+                        accounts.token_metadata_program.to_account_info(),
+                        VerifyCollection {
+                            payer: accounts.payer.to_account_info(),
+                            metadata: accounts.metadata.to_account_info(),
+                            collection_authority: collection_authority.to_account_info(),
+                            collection_mint: collection_mint.to_account_info(),
+                            collection_metadata: collection_metadata.to_account_info(),
+                            collection_master_edition: collection_master_edition.to_account_info(),
+                        },
+                    ),
+                    None,
+                )?;
+            }
+        }
+
+        // The mint is created with itself as freeze authority, which is dropped unless requested
+        if !metadata.freezable {
+            set_authority(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    SetAuthority {
+                        current_authority: ctx.accounts.mint.to_account_info(),
+                        account_or_mint: ctx.accounts.mint.to_account_info(),
+                    },
+                    &signer,
+                ),
+                AuthorityType::FreezeAccount,
+                None,
+            )?;
+        }
+
         msg!("Token mint created successfully.");
 
         Ok(())
     }
 
+    // Update the metadata of an LP Token, signed by its mint PDA
+    pub fn update_token_metadata(
+        ctx: Context<UpdateTokenMetadata>,
+        params: UpdateTokenMetadataParams,
+    ) -> Result<()> {
+        let vault = ctx.accounts.vault_account.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
+        let signer = [&seeds[..]];
+
+        // Keep the current values of every field that is not being changed
+        let current = &ctx.accounts.metadata;
+        let token_data: DataV2 = DataV2 {
+            name: params.name.unwrap_or_else(|| current.name.trim_end_matches('\0').to_string()),
+            symbol: params.symbol.unwrap_or_else(|| current.symbol.trim_end_matches('\0').to_string()),
+            uri: params.uri.unwrap_or_else(|| current.uri.trim_end_matches('\0').to_string()),
+            seller_fee_basis_points: current.seller_fee_basis_points,
+            creators: current.creators.clone(),
+            collection: current.collection.clone(),
+            uses: current.uses.clone(),
+        };
+
+        let metadata_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_metadata_program.to_account_info(),
+            UpdateMetadataAccountsV2 {
+                metadata: ctx.accounts.metadata.to_account_info(),
+                update_authority: ctx.accounts.mint.to_account_info(),
+            },
+            &signer
+        );
+
+        // Handing over the update authority or making the metadata immutable
+        // means this instruction can no longer update it.
+        update_metadata_accounts_v2(
+            metadata_ctx,
+            params.new_update_authority,
+            Some(token_data),
+            None,
+            params.is_mutable,
+        )?;
+
+        msg!("Token metadata updated successfully.");
+        Ok(())
+    }
+
     // Deposit SOL to the vault, and gain SPL tokens as a reward
     pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault_account.exit_mode {
+            return Err(Errors::ExitModeActive.into());
+        }
+        if ctx.accounts.vault_account.paused.deposits {
+            return Err(Errors::DepositsPaused.into());
+        }
+        if ctx.accounts.vault_account.wrap_sol {
+            return Err(Errors::WrappedSolEnabled.into());
+        }
+        if ctx.accounts.destination.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+
+        // The authority must have set up the vault's SOL LP mint
+        if ctx.accounts.vault_account.lp_mint != ctx.accounts.mint.key() {
+            return Err(Errors::LpMintNotInitialized.into());
+        }
+
         // Transfer SOL to the vault
         let cpi_program = ctx.accounts.system_program.to_account_info();
         let cpi_accounts = system_program::Transfer {
@@ -92,14 +374,29 @@ pub mod wallet {
         let cpi_context = CpiContext::new(cpi_program, cpi_accounts);
         system_program::transfer(cpi_context, amount)?;
 
+        // Calculate LP Tokens to mint from the vault's assets before this deposit
+        let lp_tokens_to_mint = shares_for_deposit(
+            amount,
+            ctx.accounts.vault_account.balance,
+            ctx.accounts.mint.supply,
+            native_mint::DECIMALS,
+            ctx.accounts.vault_account.lp_decimals,
+        )?;
+
         // Update the vault balance
-        ctx.accounts.vault_account.balance += amount;
+        ctx.accounts.vault_account.balance = ctx.accounts.vault_account.balance
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
 
-        // Calculate LP Tokens to mint
-        let lp_tokens_to_mint = amount; // 1 per SOL
+        // Record the deposit on the user's position
+        let now = Clock::get()?.unix_timestamp;
+        let position = &mut ctx.accounts.position;
+        position.open(ctx.accounts.vault_account.key(), ctx.accounts.user.key(), ctx.bumps.position, now);
+        position.record_deposit(native_mint::ID, amount, lp_tokens_to_mint, now)?;
 
         // Mint LP Tokens to the user
-        let seeds = &["mint".as_bytes(), &[ctx.bumps.mint]];
+        let vault = ctx.accounts.vault_account.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), native_mint::ID.as_ref(), &[ctx.bumps.mint]];
         let signer = [&seeds[..]];
 
         mint_to(
@@ -119,17 +416,47 @@ pub mod wallet {
         Ok(())
     }
 
-    // Withdraw SOL from the vault
+    // Withdraw SOL from the vault to the position owner, or the recipient they approved
     pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault_account.paused.sol_withdrawals {
+            return Err(Errors::SolWithdrawalsPaused.into());
+        }
+        if ctx.accounts.vault_account.wrap_sol {
+            return Err(Errors::WrappedSolEnabled.into());
+        }
+        if ctx.accounts.source.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+        // LP tokens received by transfer come without a position
+        let (vault, owner) = (ctx.accounts.vault_account.key(), ctx.accounts.owner.key());
+        ctx.accounts.position.open(vault, owner, ctx.bumps.position, Clock::get()?.unix_timestamp);
+        ctx.accounts.position.check_recipient(&ctx.accounts.user.key())?;
+
+        // Lamports above the rent-exempt minimum are the only SOL the vault can pay out
+        let vault_info = ctx.accounts.vault_account.to_account_info();
+        let rent_exempt_reserve = Rent::get()?.minimum_balance(vault_info.data_len());
+        let available = vault_info.lamports().saturating_sub(rent_exempt_reserve);
+        if amount > available {
+            return Err(Errors::InsufficientLiquidity.into());
+        }
+
         // Check if the vault has sufficient balance
         if ctx.accounts.vault_account.balance < amount {
             return Err(Errors::InsufficientBalance.into());
         }
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.vault_account.sol_outflow.record(amount, now)?;
+
+        // Calculate LP Tokens to burn for the requested amount of SOL
+        let lp_tokens_to_burn = shares_for_withdraw(
+            amount,
+            ctx.accounts.vault_account.balance,
+            ctx.accounts.mint.supply,
+        )?;
 
-        // Calculate LP Tokens to burn
-        let lp_tokens_to_burn = amount; // 1 per SOL
         // Burn the amount of tokens owned by the user
-        let seeds = &["mint".as_bytes(), &[ctx.bumps.mint]];
+        let vault = ctx.accounts.vault_account.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), native_mint::ID.as_ref(), &[ctx.bumps.mint]];
         let signer = [&seeds[..]];
 
         burn(
@@ -138,7 +465,7 @@ pub mod wallet {
                 Burn {
                     mint: ctx.accounts.mint.to_account_info(),
                     from: ctx.accounts.source.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
                 },
                 &signer,
             ),
@@ -164,6 +491,7 @@ pub mod wallet {
 
         // Bookkeeping: Update the vault's balance.
         ctx.accounts.vault_account.balance -= amount;
+        ctx.accounts.position.record_withdraw(native_mint::ID, amount, lp_tokens_to_burn, now);
 
         msg!(
             "Withdrawn {} SOL from the vault by burning {} LP Tokens.", 
@@ -174,18 +502,89 @@ pub mod wallet {
     }
 
     pub fn deposit_spl(ctx: Context<DepositSpl>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault_account.exit_mode {
+            return Err(Errors::ExitModeActive.into());
+        }
+        if ctx.accounts.vault_account.paused.deposits {
+            return Err(Errors::DepositsPaused.into());
+        }
+        if ctx.accounts.user_lp_ata.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+
+        // Only registered and enabled assets can be deposited
+        let config = ctx.accounts.registry
+            .find(&ctx.accounts.asset_mint.key())
+            .ok_or(Errors::AssetNotRegistered)?;
+        if config.lp_mint != ctx.accounts.mint.key() {
+            return Err(Errors::LpMintNotInitialized.into());
+        }
+        if !config.enabled {
+            return Err(Errors::AssetDisabled.into());
+        }
+        if amount < config.min_deposit {
+            return Err(Errors::DepositBelowMinimum.into());
+        }
+        let deposit_cap = config.deposit_cap;
+        let asset_decimals = config.asset_decimals;
+        let lp_decimals = config.lp_decimals;
+
         // Transfer SPL tokens from the user's ATA to vault's ATA
-        let cpi_accounts = SplTransfer {
+        let vault_ata_before = ctx.accounts.vault_ata.amount;
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.user_ata.to_account_info(),
+            mint: ctx.accounts.asset_mint.to_account_info(),
             to: ctx.accounts.vault_ata.to_account_info(),
             authority: ctx.accounts.user.to_account_info(),
         };
         let cpi_context = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        token::transfer(cpi_context, amount)?;
+        transfer_checked(cpi_context, amount, ctx.accounts.asset_mint.decimals)?;
+
+        // A Token-2022 transfer fee is withheld from the vault's side, so only credit what arrived
+        ctx.accounts.vault_ata.reload()?;
+        let received = ctx.accounts.vault_ata.amount
+            .checked_sub(vault_ata_before)
+            .ok_or(Errors::NumericalOverflow)?;
+        if received == 0 {
+            return Err(Errors::ZeroShares.into());
+        }
+        let reserve_after = ctx.accounts.reserve.balance
+            .checked_add(received)
+            .ok_or(Errors::NumericalOverflow)?;
+        if deposit_cap > 0 && reserve_after > deposit_cap {
+            return Err(Errors::DepositCapExceeded.into());
+        }
+
+        // Mint LP tokens based on the reserve's recorded balance, not the ATA amount
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.asset_mint == Pubkey::default() {
+            reserve.version = ACCOUNT_VERSION;
+            reserve.vault = ctx.accounts.vault_account.key();
+            reserve.asset_mint = ctx.accounts.asset_mint.key();
+            reserve.lp_mint = ctx.accounts.mint.key();
+            reserve.bump = ctx.bumps.reserve;
+            ctx.accounts.vault_account.open_reserve()?;
+        }
+        let lp_tokens_to_mint = shares_for_deposit(
+            received,
+            reserve.balance,
+            ctx.accounts.mint.supply,
+            asset_decimals,
+            lp_decimals,
+        )?;
+
+        // Bookkeeping: Update the reserve
+        reserve.record_deposit(received, lp_tokens_to_mint)?;
+
+        // Record the deposit on the user's position
+        let now = Clock::get()?.unix_timestamp;
+        let position = &mut ctx.accounts.position;
+        position.open(ctx.accounts.vault_account.key(), ctx.accounts.user.key(), ctx.bumps.position, now);
+        position.record_deposit(ctx.accounts.asset_mint.key(), received, lp_tokens_to_mint, now)?;
 
-        // Mint LP tokens based on the deposited SPL tokens
-        let lp_tokens_to_mint = amount; // 1 LP token per 1 SPL token deposited
-        let seeds = &["mint".as_bytes(), &[ctx.bumps.mint]];
+        let vault = ctx.accounts.vault_account.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
         let signer = [&seeds[..]];
 
         mint_to(
@@ -201,20 +600,49 @@ pub mod wallet {
             lp_tokens_to_mint,
         )?;
 
-        msg!("Deposited {} SPL tokens and minted {} LP tokens.", amount, lp_tokens_to_mint);
+        msg!(
+            "Deposited {} SPL tokens ({} received) and minted {} LP tokens.",
+            amount,
+            received,
+            lp_tokens_to_mint
+        );
         Ok(())
     }
 
-    // Withdraw SPL Tokens from the vault
+    // Withdraw SPL Tokens from the vault to the position owner, or the recipient they approved.
+    // `amount` leaves the vault and is what the LP tokens are burned for. Any Token-2022
+    // transfer fee is paid by the recipient, who receives `amount` minus the fee.
     pub fn withdraw_spl(ctx: Context<WithdrawSpl>, amount: u64) -> Result<()> {
-        // Check if the vault has sufficient SPL balance
-        if ctx.accounts.vault_ata.amount < amount {
+        if ctx.accounts.vault.paused.spl_withdrawals {
+            return Err(Errors::SplWithdrawalsPaused.into());
+        }
+        if ctx.accounts.user_lp_ata.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+        // LP tokens received by transfer come without a position
+        let (vault, owner) = (ctx.accounts.vault.key(), ctx.accounts.owner.key());
+        ctx.accounts.position.open(vault, owner, ctx.bumps.position, Clock::get()?.unix_timestamp);
+        ctx.accounts.position.check_recipient(&ctx.accounts.user.key())?;
+
+        // Check if the reserve, and the token account backing it, have sufficient SPL balance
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.balance < amount || ctx.accounts.vault_ata.amount < amount {
             return Err(Errors::InsufficientBalance.into());
         }
 
+        let now = Clock::get()?.unix_timestamp;
+        reserve.outflow.record(amount, now)?;
+
         // Burn LP Tokens
-        let lp_tokens_to_burn = amount;
-        let seeds = &["mint".as_bytes(), &[ctx.bumps.mint]];
+        let lp_tokens_to_burn = shares_for_withdraw(amount, reserve.balance, ctx.accounts.mint.supply)?;
+
+        // Bookkeeping: Update the reserve
+        reserve.record_withdraw(amount, lp_tokens_to_burn)?;
+        ctx.accounts.position.record_withdraw(ctx.accounts.asset_mint.key(), amount, lp_tokens_to_burn, now);
+
+        let vault = ctx.accounts.vault.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
         let signer = [&seeds[..]];
         burn(
             CpiContext::new_with_signer(
@@ -222,7 +650,7 @@ pub mod wallet {
                 Burn {
                     mint: ctx.accounts.mint.to_account_info(),
                     from: ctx.accounts.user_lp_ata.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
                 },
                 &signer,
             ),
@@ -230,11 +658,17 @@ pub mod wallet {
         )?;
 
         // SPL Transfer
-        let bump = ctx.bumps.vault;
-        let vault_seeds = &["myvault".as_bytes(), &[bump]];
+        let vault_id = ctx.accounts.vault.vault_id.to_le_bytes();
+        let vault_seeds = &[
+            "myvault".as_bytes(),
+            ctx.accounts.vault.creator.as_ref(),
+            vault_id.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
         let vault_signer = &[&vault_seeds[..]];
-        let cpi_accounts = SplTransfer {
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.vault_ata.to_account_info(),
+            mint: ctx.accounts.asset_mint.to_account_info(),
             to: ctx.accounts.user_ata.to_account_info(),
             authority: ctx.accounts.vault.to_account_info(),
         };
@@ -243,199 +677,3445 @@ pub mod wallet {
             cpi_accounts,
             vault_signer
         );
-        token::transfer(cpi_context, amount)?;
+        transfer_checked(cpi_context, amount, ctx.accounts.asset_mint.decimals)?;
 
         msg!(
-            "Withdrawn {} SOL from the vault by burning {} LP Tokens.", 
-            amount, 
+            "Withdrawn {} of {} from the vault by burning {} LP Tokens.",
+            amount,
+            ctx.accounts.asset_mint.key(),
             lp_tokens_to_burn
         );
         Ok(())
     }
 
-}
+    // Switch SOL deposits between lamports on the vault and wrapped SOL in the vault's ATA.
+    // Only possible while no SOL LP tokens are outstanding, so the two never mix.
+    pub fn set_sol_wrapping(ctx: Context<SetSolWrapping>, enabled: bool) -> Result<()> {
+        if ctx.accounts.vault_account.exit_mode {
+            return Err(Errors::ExitModeActive.into());
+        }
+        if ctx.accounts.vault_account.balance > 0 || ctx.accounts.mint.supply > 0 {
+            return Err(Errors::SolDepositsOutstanding.into());
+        }
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    #[account(
-        init,
-        seeds = [b"myvault".as_ref()],
-        bump,
-        payer = authority,
-        space = 8 + 32 + 8 // Discriminator + Pubkey + u64 for balance
-    )]
-    pub vault_account: Box<Account<'info, Vault>>,
-    pub rent: Sysvar<'info, Rent>,
-    pub system_program: Program<'info, System>,
-}
+        ctx.accounts.vault_account.wrap_sol = enabled;
 
-#[derive(Accounts)]
-pub struct Deposit<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(
-        mut,
-        seeds = [b"myvault".as_ref()],
-        bump
-    )]
-    pub vault_account: Box<Account<'info, Vault>>,
-    #[account(
-        mut,
-        seeds = [b"mint"],
-        bump
-    )]
-    pub mint: Account<'info, Mint>,
-    #[account(
-        init_if_needed,
-        payer = user,
-        associated_token::mint = mint,
-        associated_token::authority = user,
-    )]
-    pub destination: Account<'info, TokenAccount>,
-    pub rent: Sysvar<'info, Rent>,
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-}
+        msg!("SOL wrapping set to {}.", enabled);
+        Ok(())
+    }
 
+    // Deposit SOL as wrapped SOL, accounted for in the native mint's reserve like any SPL asset
+    pub fn deposit_wrapped_sol(ctx: Context<DepositWrappedSol>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault_account.exit_mode {
+            return Err(Errors::ExitModeActive.into());
+        }
+        if ctx.accounts.vault_account.paused.deposits {
+            return Err(Errors::DepositsPaused.into());
+        }
+        if !ctx.accounts.vault_account.wrap_sol {
+            return Err(Errors::WrappedSolDisabled.into());
+        }
+        if ctx.accounts.destination.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+        if ctx.accounts.vault_account.lp_mint != ctx.accounts.mint.key() {
+            return Err(Errors::LpMintNotInitialized.into());
+        }
 
-#[account]
-pub struct Vault {
-    pub authority: Pubkey,
-    pub balance: u64,
-}
+        // Wrap the SOL: move the lamports into the vault's wSOL account and sync its amount
+        let cpi_accounts = system_program::Transfer {
+            from: ctx.accounts.user.to_account_info(),
+            to: ctx.accounts.vault_ata.to_account_info(),
+        };
+        let cpi_context = CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts);
+        system_program::transfer(cpi_context, amount)?;
+        sync_native(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.vault_ata.to_account_info(),
+            },
+        ))?;
 
-#[derive(Accounts)]
-#[instruction(params: InitTokenParams)]
-pub struct InitToken<'info> {
-    /// New Metaplex Account being created
-    #[account(mut)]
-    pub metadata: UncheckedAccount<'info>,
-    #[account(
-        init,
-        seeds = [b"mint"],
-        bump,
-        payer = payer,
-        mint::decimals = params.decimals,
-        mint::authority = mint,
-    )]
-    pub mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub rent: Sysvar<'info, Rent>,
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub token_metadata_program: Program<'info, Metaplex>,
-}
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.asset_mint == Pubkey::default() {
+            reserve.version = ACCOUNT_VERSION;
+            reserve.vault = ctx.accounts.vault_account.key();
+            reserve.asset_mint = native_mint::ID;
+            reserve.lp_mint = ctx.accounts.mint.key();
+            reserve.bump = ctx.bumps.reserve;
+            ctx.accounts.vault_account.open_reserve()?;
+        }
+        let lp_tokens_to_mint = shares_for_deposit(
+            amount,
+            reserve.balance,
+            ctx.accounts.mint.supply,
+            native_mint::DECIMALS,
+            ctx.accounts.vault_account.lp_decimals,
+        )?;
 
-// Define the init token params
-#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
-pub struct InitTokenParams {
-    pub name: String,
-    pub symbol: String,
-    pub uri: String,
-    pub decimals: u8,
-}
+        // Bookkeeping: Update the reserve
+        reserve.record_deposit(amount, lp_tokens_to_mint)?;
 
-// Withdraw Context
-#[derive(Accounts)]
-pub struct Withdraw<'info> {
-    #[account(
-        mut,
-        seeds = [b"myvault".as_ref()],
-        bump
-    )]
-    pub vault_account: Box<Account<'info, Vault>>,
-    #[account(mut)]
-    pub user: AccountInfo<'info>,
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    #[account(
-        mut,
-        seeds = [b"mint"],
-        bump
-    )]
-    pub mint: Account<'info, Mint>,
-    #[account(
-        mut,
-        associated_token::mint = mint,
-        associated_token::authority = user,
-    )]
-    pub source: Account<'info, TokenAccount>, // User's LP token account
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+        // Record the deposit on the user's position
+        let now = Clock::get()?.unix_timestamp;
+        let position = &mut ctx.accounts.position;
+        position.open(ctx.accounts.vault_account.key(), ctx.accounts.user.key(), ctx.bumps.position, now);
+        position.record_deposit(native_mint::ID, amount, lp_tokens_to_mint, now)?;
 
-#[error_code]
-pub enum Errors {
-    #[msg("Insufficient balance in the vault.")]
-    InsufficientBalance,
-    #[msg("Numerical overflow occurred.")]
-    NumericalOverflow,
-}
+        let vault = ctx.accounts.vault_account.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), native_mint::ID.as_ref(), &[ctx.bumps.mint]];
+        let signer = [&seeds[..]];
 
-#[derive(Accounts)]
-pub struct DepositSpl<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(mut)]
-    pub user_ata: Account<'info, TokenAccount>, // For SPL
-    #[account(
-        mut,
-        seeds = [b"myvault".as_ref()],
-        bump
-    )]
-    pub vault_account: Box<Account<'info, Vault>>,
-    #[account(mut)]
-    pub vault_ata: Account<'info, TokenAccount>, // For SPL
-    #[account(
-        mut,
-        seeds = [b"mint"],
-        bump
-    )]
-    pub mint: Account<'info, Mint>, // LP token mint
-    #[account(
-        init_if_needed,
+        mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    authority: ctx.accounts.mint.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                },
+                &signer,
+            ),
+            lp_tokens_to_mint,
+        )?;
+
+        msg!("Deposited {} wrapped SOL and minted {} LP tokens.", amount, lp_tokens_to_mint);
+        Ok(())
+    }
+
+    // Withdraw wrapped SOL from the vault and unwrap it to native SOL for the recipient
+    pub fn withdraw_wrapped_sol(ctx: Context<WithdrawWrappedSol>, amount: u64) -> Result<()> {
+        if ctx.accounts.vault.paused.sol_withdrawals {
+            return Err(Errors::SolWithdrawalsPaused.into());
+        }
+        if !ctx.accounts.vault.wrap_sol {
+            return Err(Errors::WrappedSolDisabled.into());
+        }
+        if ctx.accounts.source.is_frozen() {
+            return Err(Errors::LpAccountFrozen.into());
+        }
+        // LP tokens received by transfer come without a position
+        let (vault, owner) = (ctx.accounts.vault.key(), ctx.accounts.owner.key());
+        ctx.accounts.position.open(vault, owner, ctx.bumps.position, Clock::get()?.unix_timestamp);
+        ctx.accounts.position.check_recipient(&ctx.accounts.user.key())?;
+
+        // Check if the reserve, and the token account backing it, have sufficient balance
+        let reserve = &mut ctx.accounts.reserve;
+        if reserve.balance < amount || ctx.accounts.vault_ata.amount < amount {
+            return Err(Errors::InsufficientBalance.into());
+        }
+
+        // Wrapped and native SOL share the vault's SOL outflow limit
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.vault.sol_outflow.record(amount, now)?;
+
+        let lp_tokens_to_burn = shares_for_withdraw(amount, reserve.balance, ctx.accounts.mint.supply)?;
+
+        // Bookkeeping: Update the reserve
+        reserve.record_withdraw(amount, lp_tokens_to_burn)?;
+        ctx.accounts.position.record_withdraw(native_mint::ID, amount, lp_tokens_to_burn, now);
+
+        burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.mint.to_account_info(),
+                    from: ctx.accounts.source.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            lp_tokens_to_burn,
+        )?;
+
+        // Move the wrapped SOL into the temporary account and close it into the vault,
+        // which unwraps it to lamports
+        let vault_id = ctx.accounts.vault.vault_id.to_le_bytes();
+        let vault_seeds = &[
+            "myvault".as_bytes(),
+            ctx.accounts.vault.creator.as_ref(),
+            vault_id.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let vault_signer = &[&vault_seeds[..]];
+        let rent_lamports = ctx.accounts.unwrap_account.to_account_info().lamports();
+
+        transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.vault_ata.to_account_info(),
+                    mint: ctx.accounts.native_mint.to_account_info(),
+                    to: ctx.accounts.unwrap_account.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                },
+                vault_signer,
+            ),
+            amount,
+            ctx.accounts.native_mint.decimals,
+        )?;
+        close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.unwrap_account.to_account_info(),
+                destination: ctx.accounts.vault.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            vault_signer,
+        ))?;
+
+        // Pay the SOL to the recipient and refund the temporary account's rent to the owner
+        let vault_info = ctx.accounts.vault.to_account_info();
+        let user = &ctx.accounts.user;
+        let owner = ctx.accounts.owner.to_account_info();
+        let post_vault = vault_info
+            .lamports()
+            .checked_sub(amount)
+            .and_then(|lamports| lamports.checked_sub(rent_lamports))
+            .ok_or(Errors::NumericalOverflow)?;
+        let post_user = user
+            .lamports()
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        **vault_info.try_borrow_mut_lamports()? = post_vault;
+        **user.try_borrow_mut_lamports()? = post_user;
+        let post_owner = owner
+            .lamports()
+            .checked_add(rent_lamports)
+            .ok_or(Errors::NumericalOverflow)?;
+        **owner.try_borrow_mut_lamports()? = post_owner;
+
+        msg!(
+            "Withdrawn {} wrapped SOL from the vault by burning {} LP Tokens.",
+            amount,
+            lp_tokens_to_burn
+        );
+        Ok(())
+    }
+
+    // Propose a new vault authority, who must accept it. The default Pubkey cancels a proposal.
+    pub fn propose_authority(ctx: Context<UpdateVault>, new_authority: Pubkey) -> Result<()> {
+        // Several authority instructions create or pay for accounts, which a multisig proposal
+        // cannot do, so the multisig only ever holds the other roles.
+        let (multisig, _) = Pubkey::find_program_address(
+            &[b"multisig".as_ref(), ctx.accounts.vault_account.key().as_ref()],
+            ctx.program_id,
+        );
+        if new_authority == multisig {
+            return Err(Errors::MultisigCannotBeAuthority.into());
+        }
+        ctx.accounts.vault_account.pending_authority = new_authority;
+
+        msg!("Authority transfer to {} proposed.", new_authority);
+        Ok(())
+    }
+
+    // Complete an authority transfer, signed by the proposed authority
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let vault = &mut ctx.accounts.vault_account;
+        let previous = vault.authority;
+        vault.accept_authority();
+
+        msg!("Authority transferred from {} to {}.", previous, vault.authority);
+        Ok(())
+    }
+
+    // Assign a role of the vault to a key, or leave it unassigned with the default Pubkey
+    pub fn set_role(ctx: Context<UpdateVault>, role: VaultRole, key: Pubkey) -> Result<()> {
+        ctx.accounts.vault_account.set_role(role, key);
+
+        msg!("{:?} set to {}.", role, key);
+        Ok(())
+    }
+
+    // Pause or resume deposits, SOL withdrawals, SPL withdrawals and LP mint creation
+    pub fn set_pause(ctx: Context<SetPause>, paused: PauseFlags) -> Result<()> {
+        let signer = ctx.accounts.signer.key();
+        let vault = &mut ctx.accounts.vault_account;
+        let is_guardian = vault.guardian != Pubkey::default() && signer == vault.guardian;
+        if signer != vault.authority && !is_guardian {
+            return Err(Errors::UnauthorizedPauser.into());
+        }
+
+        vault.paused = paused;
+
+        emit!(PauseUpdated {
+            vault: vault.key(),
+            paused,
+            updated_by: signer,
+        });
+        msg!("Pause flags set to {:?}.", paused);
+        Ok(())
+    }
+
+    // Wind the vault down. Deposits, SOL wrapping changes and treasury payouts stop for good,
+    // and LP holders can redeem with `exit_redeem`.
+    pub fn enable_exit_mode(ctx: Context<UpdateVault>) -> Result<()> {
+        ctx.accounts.vault_account.exit_mode = true;
+
+        emit!(ExitModeEnabled {
+            vault: ctx.accounts.vault_account.key(),
+            enabled_by: ctx.accounts.authority.key(),
+        });
+        msg!("Exit mode enabled.");
+        Ok(())
+    }
+
+    // Redeem all of the owner's LP tokens for their share of the vault's SOL, and of each
+    // SPL asset passed in `remaining_accounts`. Pause flags and outflow limits do not apply,
+    // so a wind-down cannot trap funds.
+    //
+    // Each SPL asset takes `EXIT_ASSET_ACCOUNTS` accounts: asset mint, LP mint, reserve, vault
+    // ATA, recipient token account, owner's LP token account and the asset's token program.
+    // Wrapped SOL is redeemed as an SPL asset of the native mint.
+    pub fn exit_redeem<'info>(ctx: Context<'_, '_, 'info, 'info, ExitRedeem<'info>>) -> Result<()> {
+        if !ctx.accounts.vault.exit_mode {
+            return Err(Errors::ExitModeInactive.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+
+        // LP tokens received by transfer come without a position, and deposits are closed
+        let position = &mut ctx.accounts.position;
+        position.open(ctx.accounts.vault.key(), ctx.accounts.owner.key(), ctx.bumps.position, now);
+        position.check_recipient(&ctx.accounts.user.key())?;
+        let assets = ctx.remaining_accounts.chunks_exact(EXIT_ASSET_ACCOUNTS);
+        if !assets.remainder().is_empty() {
+            return Err(Errors::MissingExitAccounts.into());
+        }
+
+        // SOL held as lamports on the vault
+        if let Some(mint) = ctx.accounts.mint.as_ref() {
+            let source = ctx.accounts.source.as_ref().ok_or(Errors::MissingExitAccounts)?;
+            let token_program = ctx.accounts.token_program.as_ref().ok_or(Errors::MissingExitAccounts)?;
+            if source.mint != mint.key() || source.owner != ctx.accounts.owner.key() {
+                return Err(Errors::ExitAccountMismatch.into());
+            }
+            if source.is_frozen() {
+                return Err(Errors::LpAccountFrozen.into());
+            }
+
+            let lp_amount = source.amount;
+            let amount = assets_for_shares(lp_amount, ctx.accounts.vault.balance, mint.supply)?;
+            if !ctx.accounts.vault.wrap_sol && amount > 0 {
+                let vault_info = ctx.accounts.vault.to_account_info();
+                let rent_exempt_reserve = Rent::get()?.minimum_balance(vault_info.data_len());
+                if amount > vault_info.lamports().saturating_sub(rent_exempt_reserve) {
+                    return Err(Errors::InsufficientLiquidity.into());
+                }
+
+                burn(
+                    CpiContext::new(
+                        token_program.to_account_info(),
+                        Burn {
+                            mint: mint.to_account_info(),
+                            from: source.to_account_info(),
+                            authority: ctx.accounts.owner.to_account_info(),
+                        },
+                    ),
+                    lp_amount,
+                )?;
+
+                let user = &ctx.accounts.user;
+                let post_from = vault_info
+                    .lamports()
+                    .checked_sub(amount)
+                    .ok_or(Errors::NumericalOverflow)?;
+                let post_to = user
+                    .lamports()
+                    .checked_add(amount)
+                    .ok_or(Errors::NumericalOverflow)?;
+                **vault_info.try_borrow_mut_lamports()? = post_from;
+                **user.try_borrow_mut_lamports()? = post_to;
+
+                ctx.accounts.vault.balance -= amount;
+                ctx.accounts.position.record_withdraw(native_mint::ID, amount, lp_amount, now);
+            }
+        }
+
+        let vault_id = ctx.accounts.vault.vault_id.to_le_bytes();
+        let vault_seeds = &[
+            "myvault".as_bytes(),
+            ctx.accounts.vault.creator.as_ref(),
+            vault_id.as_ref(),
+            &[ctx.accounts.vault.bump],
+        ];
+        let vault_signer = &[&vault_seeds[..]];
+        for accounts in assets {
+            exit_redeem_asset(
+                accounts,
+                &ctx.accounts.vault,
+                &ctx.accounts.owner,
+                ctx.accounts.user.key(),
+                &mut ctx.accounts.position,
+                vault_signer,
+                now,
+            )?;
+        }
+
+        msg!("Redeemed the exit share of {}.", ctx.accounts.owner.key());
+        Ok(())
+    }
+
+    // Close an emptied vault and return the rent of its accounts to the authority. The
+    // registry must be empty, no SOL or LP tokens may be left and every reserve must be passed.
+    //
+    // Each reserve takes `CLOSE_RESERVE_ACCOUNTS` accounts: reserve, LP mint, vault ATA and the
+    // asset's token program. The reserve and the vault ATA are closed.
+    pub fn close_vault<'info>(ctx: Context<'_, '_, 'info, 'info, CloseVault<'info>>) -> Result<()> {
+        if !ctx.accounts.registry.assets.is_empty() {
+            return Err(Errors::AssetsStillRegistered.into());
+        }
+        if ctx.accounts.vault_account.balance > 0 {
+            return Err(Errors::VaultNotEmpty.into());
+        }
+        if ctx.accounts.vault_account.lp_mint != Pubkey::default() {
+            let mint = ctx.accounts.mint.as_ref().ok_or(Errors::InvalidCloseAccount)?;
+            if mint.key() != ctx.accounts.vault_account.lp_mint {
+                return Err(Errors::InvalidCloseAccount.into());
+            }
+            if mint.supply > 0 {
+                return Err(Errors::VaultNotEmpty.into());
+            }
+        }
+        let reserves = ctx.remaining_accounts.chunks_exact(CLOSE_RESERVE_ACCOUNTS);
+        if !reserves.remainder().is_empty() {
+            return Err(Errors::InvalidCloseAccount.into());
+        }
+
+        let vault_id = ctx.accounts.vault_account.vault_id.to_le_bytes();
+        let vault_seeds = &[
+            "myvault".as_bytes(),
+            ctx.accounts.vault_account.creator.as_ref(),
+            vault_id.as_ref(),
+            &[ctx.accounts.vault_account.bump],
+        ];
+        let vault_signer = &[&vault_seeds[..]];
+        let mut closed: u32 = 0;
+        for accounts in reserves {
+            close_reserve(
+                accounts,
+                &ctx.accounts.vault_account,
+                &ctx.accounts.authority,
+                vault_signer,
+            )?;
+            closed += 1;
+        }
+        if closed < ctx.accounts.vault_account.reserve_count {
+            return Err(Errors::ReservesStillOpen.into());
+        }
+
+        emit!(VaultClosed {
+            vault: ctx.accounts.vault_account.key(),
+            authority: ctx.accounts.authority.key(),
+        });
+        msg!("Vault {} closed.", ctx.accounts.vault_account.vault_id);
+        Ok(())
+    }
+
+    // Freeze an LP token account of a freezable LP mint, signed by the compliance role
+    pub fn freeze_lp_account(ctx: Context<FreezeLpAccount>) -> Result<()> {
+        ctx.accounts.check_freezable()?;
+
+        let vault = ctx.accounts.vault_account.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
+        let signer = [&seeds[..]];
+        freeze_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            FreezeAccount {
+                account: ctx.accounts.lp_account.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                authority: ctx.accounts.mint.to_account_info(),
+            },
+            &signer,
+        ))?;
+
+        emit!(LpAccountFreezeUpdated {
+            vault,
+            lp_mint: ctx.accounts.mint.key(),
+            account: ctx.accounts.lp_account.key(),
+            owner: ctx.accounts.lp_account.owner,
+            frozen: true,
+            updated_by: ctx.accounts.compliance.key(),
+        });
+        msg!("LP token account {} frozen.", ctx.accounts.lp_account.key());
+        Ok(())
+    }
+
+    // Thaw a frozen LP token account, signed by the compliance role
+    pub fn thaw_lp_account(ctx: Context<FreezeLpAccount>) -> Result<()> {
+        ctx.accounts.check_freezable()?;
+
+        let vault = ctx.accounts.vault_account.key();
+        let asset_mint = ctx.accounts.asset_mint.key();
+        let seeds = &["mint".as_bytes(), vault.as_ref(), asset_mint.as_ref(), &[ctx.bumps.mint]];
+        let signer = [&seeds[..]];
+        thaw_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            ThawAccount {
+                account: ctx.accounts.lp_account.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                authority: ctx.accounts.mint.to_account_info(),
+            },
+            &signer,
+        ))?;
+
+        emit!(LpAccountFreezeUpdated {
+            vault,
+            lp_mint: ctx.accounts.mint.key(),
+            account: ctx.accounts.lp_account.key(),
+            owner: ctx.accounts.lp_account.owner,
+            frozen: false,
+            updated_by: ctx.accounts.compliance.key(),
+        });
+        msg!("LP token account {} thawed.", ctx.accounts.lp_account.key());
+        Ok(())
+    }
+
+    // Limit how much SOL, or the asset of `reserve`, can leave the vault within `window`
+    // seconds. A `limit` of 0 removes the limit.
+    pub fn set_outflow_limit(ctx: Context<SetOutflowLimit>, window: i64, limit: u64) -> Result<()> {
+        let vault = ctx.accounts.vault_account.key();
+        let (asset_mint, outflow) = match ctx.accounts.reserve.as_mut() {
+            Some(reserve) => (reserve.asset_mint, &mut reserve.outflow),
+            None => (native_mint::ID, &mut ctx.accounts.vault_account.sol_outflow),
+        };
+        outflow.set(window, limit, Clock::get()?.unix_timestamp)?;
+
+        emit!(OutflowLimitUpdated {
+            vault,
+            asset_mint,
+            outflow: *outflow,
+            updated_by: ctx.accounts.authority.key(),
+        });
+        Ok(())
+    }
+
+    // Let the guardian, or the authority, allow more outflow for up to one window
+    pub fn raise_outflow_limit(ctx: Context<RaiseOutflowLimit>, limit: u64, duration: i64) -> Result<()> {
+        let signer = ctx.accounts.signer.key();
+        let vault = &mut ctx.accounts.vault_account;
+        if signer != vault.authority && (signer != vault.guardian || vault.guardian == Pubkey::default()) {
+            return Err(Errors::UnauthorizedLimitRaise.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let vault_key = vault.key();
+        let (asset_mint, outflow) = match ctx.accounts.reserve.as_mut() {
+            Some(reserve) => (reserve.asset_mint, &mut reserve.outflow),
+            None => (native_mint::ID, &mut vault.sol_outflow),
+        };
+        outflow.raise(limit, duration, now)?;
+
+        emit!(OutflowLimitUpdated {
+            vault: vault_key,
+            asset_mint,
+            outflow: *outflow,
+            updated_by: signer,
+        });
+        Ok(())
+    }
+
+    // Choose whether untracked funds found by `sync_balance` go to LP holders or the treasury
+    pub fn set_surplus_policy(
+        ctx: Context<UpdateFees>,
+        policy: SurplusPolicy,
+        treasury: Pubkey,
+    ) -> Result<()> {
+        if ctx.accounts.vault_account.timelocked {
+            return Err(Errors::TimelockRequired.into());
+        }
+        ctx.accounts.vault_account.set_surplus_policy(policy, treasury)?;
+
+        msg!("Surplus policy set to {:?}, treasury {}.", policy, treasury);
+        Ok(())
+    }
+
+    // Reconcile the vault's recorded SOL balance with its lamports above the rent-exempt minimum
+    pub fn sync_balance(ctx: Context<SyncBalance>) -> Result<()> {
+        // Wrapped SOL deposits are reconciled by `sync_balance_spl` on the native mint's reserve.
+        // Crediting lamports here would leave a balance no lamport LP token can redeem.
+        if ctx.accounts.vault_account.wrap_sol {
+            return Err(Errors::WrappedSolEnabled.into());
+        }
+        let vault_info = ctx.accounts.vault_account.to_account_info();
+        let rent_exempt_reserve = Rent::get()?.minimum_balance(vault_info.data_len());
+        let actual = vault_info.lamports().saturating_sub(rent_exempt_reserve);
+        let recorded = ctx.accounts.vault_account.balance;
+        let surplus = actual.saturating_sub(recorded);
+
+        let policy = ctx.accounts.vault_account.surplus_policy;
+        if surplus > 0 {
+            match policy {
+                SurplusPolicy::Yield => {
+                    ctx.accounts.vault_account.check_yield_signer(&ctx.accounts.signer.key())?;
+                    ctx.accounts.vault_account.balance = recorded
+                        .checked_add(surplus)
+                        .ok_or(Errors::NumericalOverflow)?;
+                }
+                SurplusPolicy::Treasury => {
+                    if ctx.accounts.vault_account.exit_mode {
+                        return Err(Errors::ExitModeActive.into());
+                    }
+                    let treasury = ctx.accounts.treasury
+                        .as_ref()
+                        .ok_or(Errors::TreasuryNotSet)?;
+                    if treasury.key() != ctx.accounts.vault_account.treasury {
+                        return Err(Errors::InvalidTreasury.into());
+                    }
+
+                    let post_from = vault_info
+                        .lamports()
+                        .checked_sub(surplus)
+                        .ok_or(Errors::NumericalOverflow)?;
+                    let post_to = treasury
+                        .lamports()
+                        .checked_add(surplus)
+                        .ok_or(Errors::NumericalOverflow)?;
+                    **vault_info.try_borrow_mut_lamports()? = post_from;
+                    **treasury.try_borrow_mut_lamports()? = post_to;
+                }
+            }
+        }
+
+        emit!(BalanceSynced {
+            vault: ctx.accounts.vault_account.key(),
+            asset_mint: native_mint::ID,
+            recorded,
+            actual,
+            surplus,
+            policy,
+        });
+        msg!("Synced SOL balance: recorded {}, actual {}, surplus {}.", recorded, actual, surplus);
+        Ok(())
+    }
+
+    // Reconcile an asset reserve with the tokens held in the vault's ATA
+    pub fn sync_balance_spl(ctx: Context<SyncBalanceSpl>) -> Result<()> {
+        let actual = ctx.accounts.vault_ata.amount;
+        let recorded = ctx.accounts.reserve.balance;
+        let surplus = actual.saturating_sub(recorded);
+
+        let policy = ctx.accounts.vault.surplus_policy;
+        if surplus > 0 {
+            match policy {
+                SurplusPolicy::Yield => {
+                    ctx.accounts.vault.check_yield_signer(&ctx.accounts.signer.key())?;
+                    ctx.accounts.reserve.balance = recorded
+                        .checked_add(surplus)
+                        .ok_or(Errors::NumericalOverflow)?;
+                }
+                SurplusPolicy::Treasury => {
+                    if ctx.accounts.vault.exit_mode {
+                        return Err(Errors::ExitModeActive.into());
+                    }
+                    let treasury_ata = ctx.accounts.treasury_ata
+                        .as_ref()
+                        .ok_or(Errors::TreasuryNotSet)?;
+
+                    let vault_id = ctx.accounts.vault.vault_id.to_le_bytes();
+                    let vault_seeds = &[
+                        "myvault".as_bytes(),
+                        ctx.accounts.vault.creator.as_ref(),
+                        vault_id.as_ref(),
+                        &[ctx.accounts.vault.bump],
+                    ];
+                    let vault_signer = &[&vault_seeds[..]];
+                    let cpi_accounts = TransferChecked {
+                        from: ctx.accounts.vault_ata.to_account_info(),
+                        mint: ctx.accounts.asset_mint.to_account_info(),
+                        to: treasury_ata.to_account_info(),
+                        authority: ctx.accounts.vault.to_account_info(),
+                    };
+                    let cpi_context = CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        cpi_accounts,
+                        vault_signer
+                    );
+                    transfer_checked(cpi_context, surplus, ctx.accounts.asset_mint.decimals)?;
+                }
+            }
+        }
+
+        emit!(BalanceSynced {
+            vault: ctx.accounts.vault.key(),
+            asset_mint: ctx.accounts.asset_mint.key(),
+            recorded,
+            actual,
+            surplus,
+            policy,
+        });
+        msg!("Synced SPL balance: recorded {}, actual {}, surplus {}.", recorded, actual, surplus);
+        Ok(())
+    }
+
+    // Approve a recipient for the owner's withdrawals, or clear it with the default Pubkey
+    pub fn set_withdraw_recipient(ctx: Context<SetWithdrawRecipient>, recipient: Pubkey) -> Result<()> {
+        let position = &mut ctx.accounts.position;
+        position.approved_recipient = recipient;
+        position.updated_at = Clock::get()?.unix_timestamp;
+
+        msg!("Withdraw recipient set to {}.", recipient);
+        Ok(())
+    }
+
+    // Accept deposits of an SPL mint into the vault
+    pub fn add_asset(ctx: Context<AddAsset>, params: AssetParams) -> Result<()> {
+        if ctx.accounts.vault_account.timelocked {
+            return Err(Errors::TimelockRequired.into());
+        }
+        let mint = ctx.accounts.asset_mint.key();
+        ctx.accounts.registry.add(
+            mint,
+            ctx.accounts.asset_mint.decimals,
+            &params,
+            &ctx.accounts.lp_mint,
+        )?;
+
+        msg!("Asset {} added to the vault registry.", mint);
+        Ok(())
+    }
+
+    // Change the settings of a registered SPL mint. Disabling it blocks deposits only.
+    pub fn update_asset(ctx: Context<ManageAsset>, params: AssetParams) -> Result<()> {
+        if ctx.accounts.vault_account.timelocked {
+            return Err(Errors::TimelockRequired.into());
+        }
+        let mint = ctx.accounts.asset_mint.key();
+        ctx.accounts.registry.update(&mint, &params)?;
+
+        msg!("Asset {} updated (enabled: {}).", mint, params.enabled);
+        Ok(())
+    }
+
+    // Stop accepting an SPL mint. The vault must no longer hold any of it.
+    pub fn remove_asset(ctx: Context<RemoveAsset>) -> Result<()> {
+        if ctx.accounts.vault_account.timelocked {
+            return Err(Errors::TimelockRequired.into());
+        }
+        AssetReserve::check_empty(&ctx.accounts.reserve)?;
+
+        let mint = ctx.accounts.asset_mint.key();
+        ctx.accounts.registry.remove(&mint)?;
+
+        msg!("Asset {} removed from the vault registry.", mint);
+        Ok(())
+    }
+
+    // Create the multisig of a vault. It only gains power once the authority hands it roles
+    // with `set_role`, or the authority itself through `propose_authority`.
+    pub fn create_multisig(
+        ctx: Context<CreateMultisig>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        Multisig::validate(&signers, threshold)?;
+
+        let multisig = &mut ctx.accounts.multisig;
+        multisig.version = ACCOUNT_VERSION;
+        multisig.vault = ctx.accounts.vault_account.key();
+        multisig.signers = signers;
+        multisig.threshold = threshold;
+        multisig.proposal_count = 0;
+        multisig.signers_version = 0;
+        multisig.bump = ctx.bumps.multisig;
+
+        msg!("Multisig created with threshold {} of {}.", threshold, multisig.signers.len());
+        Ok(())
+    }
+
+    // Propose an admin action. The proposer must be a signer and approves it right away.
+    pub fn create_proposal(ctx: Context<CreateProposal>, action: MultisigAction) -> Result<()> {
+        let multisig = &mut ctx.accounts.multisig;
+        let index = multisig.signer_index(&ctx.accounts.proposer.key())?;
+
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.version = ACCOUNT_VERSION;
+        proposal.multisig = multisig.key();
+        proposal.id = multisig.proposal_count;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.action = action;
+        proposal.approvals = vec![false; multisig.signers.len()];
+        proposal.approvals[index] = true;
+        proposal.executed = false;
+        proposal.signers_version = multisig.signers_version;
+        proposal.created_at = Clock::get()?.unix_timestamp;
+        proposal.bump = ctx.bumps.proposal;
+
+        multisig.proposal_count = multisig.proposal_count
+            .checked_add(1)
+            .ok_or(Errors::NumericalOverflow)?;
+
+        msg!("Proposal {} created.", proposal.id);
+        Ok(())
+    }
+
+    // Approve a proposal as one of the multisig signers
+    pub fn approve_proposal(ctx: Context<ApproveProposal>) -> Result<()> {
+        let multisig = &ctx.accounts.multisig;
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.check_open(multisig)?;
+
+        let index = multisig.signer_index(&ctx.accounts.signer.key())?;
+        if proposal.approvals[index] {
+            return Err(Errors::AlreadyApproved.into());
+        }
+        proposal.approvals[index] = true;
+
+        msg!("Proposal {} approved by {}.", proposal.id, ctx.accounts.signer.key());
+        Ok(())
+    }
+
+    // Carry out a proposal once it has enough approvals. The multisig must hold the role
+    // the action needs, exactly as a single key would.
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> Result<()> {
+        let multisig_key = ctx.accounts.multisig.key();
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.check_open(&ctx.accounts.multisig)?;
+
+        let approvals = proposal.approvals.iter().filter(|approved| **approved).count();
+        if approvals < ctx.accounts.multisig.threshold as usize {
+            return Err(Errors::NotEnoughApprovals.into());
+        }
+        proposal.executed = true;
+
+        let vault_key = ctx.accounts.vault_account.key();
+        let vault = &mut ctx.accounts.vault_account;
+        match proposal.action.clone() {
+            MultisigAction::SetPause { paused } => {
+                if vault.guardian != multisig_key {
+                    return Err(Errors::MultisigNotAuthorized.into());
+                }
+                vault.paused = paused;
+                emit!(PauseUpdated {
+                    vault: vault_key,
+                    paused,
+                    updated_by: multisig_key,
+                });
+            }
+            MultisigAction::ApplyChange { change } => {
+                if vault.timelocked {
+                    return Err(Errors::TimelockRequired.into());
+                }
+                if change.role_holder(vault) != multisig_key {
+                    return Err(Errors::MultisigNotAuthorized.into());
+                }
+                apply_config_change(
+                    &change,
+                    vault,
+                    vault_key,
+                    ctx.accounts.registry.as_deref_mut().map(|registry| &mut **registry),
+                    ctx.accounts.asset_mint.as_ref(),
+                    ctx.accounts.lp_mint.as_ref(),
+                    ctx.accounts.reserve.as_ref(),
+                    None,
+                    ctx.program_id,
+                )?;
+            }
+            MultisigAction::QueueChange { change } => {
+                if change.role_holder(vault) != multisig_key {
+                    return Err(Errors::MultisigNotAuthorized.into());
+                }
+                let timelock = ctx.accounts.timelock
+                    .as_mut()
+                    .ok_or(Errors::MissingProposalAccounts)?;
+                timelock.queue(vault_key, change, multisig_key, Clock::get()?.unix_timestamp)?;
+            }
+            MultisigAction::SetSigners { signers, threshold } => {
+                Multisig::validate(&signers, threshold)?;
+                let multisig = &mut ctx.accounts.multisig;
+                multisig.signers = signers;
+                multisig.threshold = threshold;
+                // Open proposals were approved by the old signers
+                multisig.signers_version = multisig.signers_version
+                    .checked_add(1)
+                    .ok_or(Errors::NumericalOverflow)?;
+            }
+        }
+
+        msg!("Proposal {} executed.", proposal.id);
+        Ok(())
+    }
+
+    // Put fee and asset changes of the vault behind a delay. Once enabled, they can only be
+    // made through `queue_change` and `execute_change`.
+    pub fn init_timelock(ctx: Context<InitTimelock>, delay: i64) -> Result<()> {
+        if delay <= 0 {
+            return Err(Errors::InvalidTimelockDelay.into());
+        }
+
+        let timelock = &mut ctx.accounts.timelock;
+        timelock.version = ACCOUNT_VERSION;
+        timelock.vault = ctx.accounts.vault_account.key();
+        timelock.delay = delay;
+        timelock.next_id = 0;
+        timelock.changes = Vec::new();
+        timelock.bump = ctx.bumps.timelock;
+        ctx.accounts.vault_account.timelocked = true;
+
+        msg!("Timelock enabled with a delay of {} seconds.", delay);
+        Ok(())
+    }
+
+    // Queue a change, signed by the role that could otherwise make it directly
+    pub fn queue_change(ctx: Context<QueueChange>, change: ConfigChange) -> Result<()> {
+        let signer = ctx.accounts.signer.key();
+        if change.role_holder(&ctx.accounts.vault_account) != signer {
+            return Err(Errors::UnauthorizedChange.into());
+        }
+
+        let vault = ctx.accounts.vault_account.key();
+        ctx.accounts.timelock.queue(vault, change, signer, Clock::get()?.unix_timestamp)?;
+        Ok(())
+    }
+
+    // Drop a queued change, signed by the vault authority
+    pub fn cancel_change(ctx: Context<CancelChange>, id: u64) -> Result<()> {
+        let vault = ctx.accounts.vault_account.key();
+        ctx.accounts.timelock.cancel(vault, id, ctx.accounts.authority.key())?;
+        Ok(())
+    }
+
+    // Apply a queued change once its delay has passed. Anyone can call it.
+    pub fn execute_change(ctx: Context<ExecuteChange>, id: u64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let timelock = &mut ctx.accounts.timelock;
+        let index = timelock.changes
+            .iter()
+            .position(|queued| queued.id == id)
+            .ok_or(Errors::ChangeNotQueued)?;
+        if now < timelock.changes[index].eta {
+            return Err(Errors::TimelockNotExpired.into());
+        }
+        let queued = timelock.changes.remove(index);
+
+        let vault_key = ctx.accounts.vault_account.key();
+        apply_config_change(
+            &queued.change,
+            &mut ctx.accounts.vault_account,
+            vault_key,
+            ctx.accounts.registry.as_deref_mut().map(|registry| &mut **registry),
+            ctx.accounts.asset_mint.as_ref(),
+            ctx.accounts.lp_mint.as_ref(),
+            ctx.accounts.reserve.as_ref(),
+            Some(&mut ctx.accounts.timelock),
+            ctx.program_id,
+        )?;
+
+        emit!(ChangeExecuted { vault: vault_key, id });
+        msg!("Queued change {} executed.", id);
+        Ok(())
+    }
+
+}
+
+// Apply a fee or asset change whose role and timelock checks have passed
+#[allow(clippy::too_many_arguments)]
+fn apply_config_change<'info>(
+    change: &ConfigChange,
+    vault: &mut Vault,
+    vault_key: Pubkey,
+    registry: Option<&mut AssetRegistry>,
+    asset_mint: Option<&InterfaceAccount<'info, Mint>>,
+    lp_mint: Option<&UncheckedAccount<'info>>,
+    reserve: Option<&UncheckedAccount<'info>>,
+    timelock: Option<&mut Timelock>,
+    program_id: &Pubkey,
+) -> Result<()> {
+    match change {
+        ConfigChange::SetSurplusPolicy { policy, treasury } => {
+            vault.set_surplus_policy(*policy, *treasury)?;
+        }
+        ConfigChange::AddAsset { mint, params } => {
+            let asset_mint = asset_mint.ok_or(Errors::MissingProposalAccounts)?;
+            if asset_mint.key() != *mint {
+                return Err(Errors::ProposalAccountMismatch.into());
+            }
+            let lp_mint = lp_mint.ok_or(Errors::MissingProposalAccounts)?;
+            let (expected_lp_mint, _) = Pubkey::find_program_address(
+                &[b"mint".as_ref(), vault_key.as_ref(), mint.as_ref()],
+                program_id,
+            );
+            if lp_mint.key() != expected_lp_mint {
+                return Err(Errors::ProposalAccountMismatch.into());
+            }
+            registry
+                .ok_or(Errors::MissingProposalAccounts)?
+                .add(*mint, asset_mint.decimals, params, lp_mint)?;
+        }
+        ConfigChange::UpdateAsset { mint, params } => {
+            registry
+                .ok_or(Errors::MissingProposalAccounts)?
+                .update(mint, params)?;
+        }
+        ConfigChange::RemoveAsset { mint } => {
+            let reserve = reserve.ok_or(Errors::MissingProposalAccounts)?;
+            let (expected_reserve, _) = Pubkey::find_program_address(
+                &[b"reserve".as_ref(), vault_key.as_ref(), mint.as_ref()],
+                program_id,
+            );
+            if reserve.key() != expected_reserve {
+                return Err(Errors::ProposalAccountMismatch.into());
+            }
+            AssetReserve::check_empty(reserve)?;
+            registry
+                .ok_or(Errors::MissingProposalAccounts)?
+                .remove(mint)?;
+        }
+        ConfigChange::SetTimelockDelay { delay } => {
+            if *delay <= 0 {
+                return Err(Errors::InvalidTimelockDelay.into());
+            }
+            timelock.ok_or(Errors::MissingProposalAccounts)?.delay = *delay;
+        }
+    }
+    Ok(())
+}
+
+// LP tokens to mint for a deposit of `amount`, given the vault's assets and LP supply
+// before the deposit. Rounds down so the depositor never receives more than their share.
+pub fn shares_for_deposit(
+    amount: u64,
+    total_assets: u64,
+    total_supply: u64,
+    asset_decimals: u8,
+    lp_decimals: u8,
+) -> Result<u64> {
+    // The first depositor sets the price at 1 whole LP token per whole asset token.
+    // Later deposits are priced from the existing ratio, which already carries the scaling.
+    if total_supply == 0 {
+        let shares = scale_amount(amount, asset_decimals, lp_decimals)?;
+        if shares == 0 {
+            return Err(Errors::ZeroShares.into());
+        }
+        return Ok(shares);
+    }
+    if total_assets == 0 {
+        return Err(Errors::InvalidSharePrice.into());
+    }
+
+    let shares = (amount as u128)
+        .checked_mul(total_supply as u128)
+        .ok_or(Errors::NumericalOverflow)?
+        / total_assets as u128;
+    let shares = u64::try_from(shares).map_err(|_| Errors::NumericalOverflow)?;
+    if shares == 0 {
+        return Err(Errors::ZeroShares.into());
+    }
+
+    Ok(shares)
+}
+
+// Convert `amount` base units between mints of different decimals, rounding down
+pub fn scale_amount(amount: u64, from_decimals: u8, to_decimals: u8) -> Result<u64> {
+    if to_decimals >= from_decimals {
+        let factor = 10u64
+            .checked_pow((to_decimals - from_decimals) as u32)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(amount.checked_mul(factor).ok_or(Errors::NumericalOverflow)?)
+    } else {
+        let factor = 10u64
+            .checked_pow((from_decimals - to_decimals) as u32)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(amount / factor)
+    }
+}
+
+// LP tokens to burn for a withdrawal of `amount`, given the vault's assets and LP supply
+// before the withdrawal. Rounds up so the vault never pays out more than the burned share.
+pub fn shares_for_withdraw(amount: u64, total_assets: u64, total_supply: u64) -> Result<u64> {
+    if total_assets == 0 || total_supply == 0 {
+        return Err(Errors::InsufficientBalance.into());
+    }
+
+    let numerator = (amount as u128)
+        .checked_mul(total_supply as u128)
+        .ok_or(Errors::NumericalOverflow)?;
+    let shares = numerator
+        .checked_add(total_assets as u128 - 1)
+        .ok_or(Errors::NumericalOverflow)?
+        / total_assets as u128;
+    let shares = u64::try_from(shares).map_err(|_| Errors::NumericalOverflow)?;
+    if shares == 0 {
+        return Err(Errors::ZeroShares.into());
+    }
+
+    Ok(shares)
+}
+
+// Assets paid out for `shares` LP tokens, rounding down
+pub fn assets_for_shares(shares: u64, total_assets: u64, total_supply: u64) -> Result<u64> {
+    if total_supply == 0 {
+        return Ok(0);
+    }
+
+    let assets = (shares as u128)
+        .checked_mul(total_assets as u128)
+        .ok_or(Errors::NumericalOverflow)?
+        / total_supply as u128;
+    u64::try_from(assets).map_err(|_| Errors::NumericalOverflow.into())
+}
+
+// Accounts `close_vault` takes for each reserve
+pub const CLOSE_RESERVE_ACCOUNTS: usize = 4;
+
+// Close an empty reserve and the vault's token account of its asset
+fn close_reserve<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    vault: &Account<'info, Vault>,
+    authority: &Signer<'info>,
+    vault_signer: &[&[&[u8]]],
+) -> Result<()> {
+    let [reserve_info, lp_mint_info, vault_ata_info, token_program] = accounts else {
+        return Err(Errors::InvalidCloseAccount.into());
+    };
+    let reserve = Account::<AssetReserve>::try_from(reserve_info)?;
+    let lp_mint = InterfaceAccount::<Mint>::try_from(lp_mint_info)?;
+    let expected_vault_ata = get_associated_token_address_with_program_id(
+        &vault.key(),
+        &reserve.asset_mint,
+        &token_program.key(),
+    );
+    if reserve.vault != vault.key()
+        || reserve.lp_mint != lp_mint.key()
+        || token_program.key() != *lp_mint_info.owner
+        || vault_ata_info.key() != expected_vault_ata
+    {
+        return Err(Errors::InvalidCloseAccount.into());
+    }
+    if reserve.balance > 0 || lp_mint.supply > 0 {
+        return Err(Errors::VaultNotEmpty.into());
+    }
+
+    // The vault ATA may never have been created, or already be closed
+    if !vault_ata_info.data_is_empty() {
+        let vault_ata = InterfaceAccount::<TokenAccount>::try_from(vault_ata_info)?;
+        if vault_ata.amount > 0 {
+            return Err(Errors::VaultNotEmpty.into());
+        }
+        close_account(CpiContext::new_with_signer(
+            token_program.clone(),
+            CloseAccount {
+                account: vault_ata_info.clone(),
+                destination: authority.to_account_info(),
+                authority: vault.to_account_info(),
+            },
+            vault_signer,
+        ))?;
+    }
+    reserve.close(authority.to_account_info())
+}
+
+// Accounts `exit_redeem` takes for each SPL asset
+pub const EXIT_ASSET_ACCOUNTS: usize = 7;
+
+// Redeem the owner's LP tokens of one SPL asset in exit mode
+fn exit_redeem_asset<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    vault: &Account<'info, Vault>,
+    owner: &Signer<'info>,
+    user: Pubkey,
+    position: &mut UserPosition,
+    vault_signer: &[&[&[u8]]],
+    now: i64,
+) -> Result<()> {
+    let [asset_mint_info, lp_mint_info, reserve_info, vault_ata_info, user_ata_info, user_lp_ata_info, token_program] =
+        accounts
+    else {
+        return Err(Errors::MissingExitAccounts.into());
+    };
+    let asset_mint = InterfaceAccount::<Mint>::try_from(asset_mint_info)?;
+    let lp_mint = InterfaceAccount::<Mint>::try_from(lp_mint_info)?;
+    let mut reserve = Account::<AssetReserve>::try_from(reserve_info)?;
+    let vault_ata = InterfaceAccount::<TokenAccount>::try_from(vault_ata_info)?;
+    let user_ata = InterfaceAccount::<TokenAccount>::try_from(user_ata_info)?;
+    let user_lp_ata = InterfaceAccount::<TokenAccount>::try_from(user_lp_ata_info)?;
+
+    // The mint's owner is a token program, so the CPIs below cannot go anywhere else
+    let expected_vault_ata = get_associated_token_address_with_program_id(
+        &vault.key(),
+        &asset_mint.key(),
+        &token_program.key(),
+    );
+    if token_program.key() != *asset_mint_info.owner
+        || reserve.vault != vault.key()
+        || reserve.asset_mint != asset_mint.key()
+        || reserve.lp_mint != lp_mint.key()
+        || vault_ata.key() != expected_vault_ata
+        || user_ata.mint != asset_mint.key()
+        || user_ata.owner != user
+        || user_lp_ata.mint != lp_mint.key()
+        || user_lp_ata.owner != owner.key()
+    {
+        return Err(Errors::ExitAccountMismatch.into());
+    }
+
+    if user_lp_ata.is_frozen() {
+        return Err(Errors::LpAccountFrozen.into());
+    }
+
+    let lp_amount = user_lp_ata.amount;
+    let amount = assets_for_shares(lp_amount, reserve.balance, lp_mint.supply)?;
+    if amount == 0 {
+        return Ok(());
+    }
+    if vault_ata.amount < amount {
+        return Err(Errors::InsufficientBalance.into());
+    }
+
+    // Bookkeeping: Update the reserve, written back before the next asset is read
+    reserve.record_withdraw(amount, lp_amount)?;
+    reserve.exit(&crate::ID)?;
+    position.record_withdraw(asset_mint.key(), amount, lp_amount, now);
+
+    burn(
+        CpiContext::new(
+            token_program.clone(),
+            Burn {
+                mint: lp_mint_info.clone(),
+                from: user_lp_ata_info.clone(),
+                authority: owner.to_account_info(),
+            },
+        ),
+        lp_amount,
+    )?;
+    transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.clone(),
+            TransferChecked {
+                from: vault_ata_info.clone(),
+                mint: asset_mint_info.clone(),
+                to: user_ata_info.clone(),
+                authority: vault.to_account_info(),
+            },
+            vault_signer,
+        ),
+        amount,
+        asset_mint.decimals,
+    )?;
+    Ok(())
+}
+
+// The vault is read by hand, as a version 1 vault does not deserialize as `Vault`
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    /// CHECK: Version 1 vault of the original program, read by `Vault::from_legacy`
+    #[account(
+        mut,
+        seeds = [b"myvault".as_ref()],
+        bump
+    )]
+    pub vault_account: UncheckedAccount<'info>,
+    /// CHECK: LP mint of the original program, which may never have been created
+    #[account(
+        seeds = [b"mint".as_ref()],
+        bump
+    )]
+    pub lp_mint: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RedeemLegacy<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"myvault".as_ref()],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"mint".as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = owner,
+        token::token_program = token_program,
+    )]
+    pub source: InterfaceAccount<'info, TokenAccount>, // Owner's LP tokens of the original vault
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        init,
+        seeds = [b"myvault".as_ref(), authority.key().as_ref(), vault_id.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+        space = Vault::SIZE
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        init,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump,
+        payer = authority,
+        space = AssetRegistry::SIZE
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"mint", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // SOL LP token mint
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = mint,
+        associated_token::authority = user,
+        associated_token::token_program = token_program,
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault_account.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+
+// Layout version of new accounts. Version 1 is the original program's single vault, which has
+// no version field and is upgraded with `migrate_vault`.
+pub const ACCOUNT_VERSION: u8 = 2;
+// Bytes allocated beyond each account's largest layout, so fields can be added without a realloc
+pub const RESERVED_SPACE: usize = 64;
+
+#[account]
+pub struct Vault {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub authority: Pubkey,
+    pub balance: u64,
+    pub creator: Pubkey, // Seed of the vault PDA
+    pub vault_id: u64,   // Seed of the vault PDA
+    pub bump: u8,
+    pub lp_mint: Pubkey, // SOL LP mint, set by `init_token`
+    pub lp_decimals: u8,
+    pub treasury: Pubkey, // Receives surplus under `SurplusPolicy::Treasury`
+    pub surplus_policy: SurplusPolicy,
+    pub wrap_sol: bool, // SOL deposits are held as wrapped SOL in the vault's ATA
+    pub guardian: Pubkey, // Can pause the vault, default Pubkey when unset
+    pub paused: PauseFlags,
+    pub pending_authority: Pubkey, // Proposed by `propose_authority`, default Pubkey when none
+    pub fee_manager: Pubkey,       // Sets the surplus policy and treasury
+    pub asset_manager: Pubkey,     // Manages the asset registry
+    pub timelocked: bool,          // Fee and asset changes go through the timelock
+    pub sol_outflow: OutflowLimit, // Shared by native and wrapped SOL withdrawals
+    pub exit_mode: bool,           // Set for good by `enable_exit_mode`
+    pub compliance: Pubkey,        // Freezes and thaws LP token accounts, default Pubkey when unset
+    pub reserve_count: u32,        // Open asset reserves, all closed by `close_vault`
+}
+
+impl Vault {
+    // Version 1, the single vault of the original program: Discriminator + Pubkey + u64
+    pub const LEGACY_SIZE: usize = 8 + 32 + 8;
+    // Discriminator + version + 8 Pubkeys + 2 u64 + bump + decimals + policy + 3 bools + pause
+    // flags + outflow limit + reserved space, of which `compliance` and `reserve_count` take 36 bytes
+    pub const SIZE: usize =
+        8 + 1 + 32 * 8 + 8 * 2 + 1 + 1 + 1 + 1 + 1 + 1 + PauseFlags::SIZE + OutflowLimit::SIZE + RESERVED_SPACE;
+
+    // Read the version 1 vault into the current layout. The authority keeps every role, and the
+    // vault winds down in exit mode.
+    pub fn from_legacy(data: &[u8], bump: u8, lp_mint: Pubkey, lp_decimals: u8) -> Result<Self> {
+        if data.len() != Self::LEGACY_SIZE || !data.starts_with(&Self::DISCRIMINATOR) {
+            return Err(Errors::UnsupportedAccountVersion.into());
+        }
+        let (authority, balance) = <(Pubkey, u64)>::deserialize(&mut &data[8..])
+            .map_err(|_| Errors::InvalidMigrationAccount)?;
+
+        Ok(Self {
+            version: ACCOUNT_VERSION,
+            authority,
+            balance,
+            creator: Pubkey::default(), // The original vault has no creator or ID seed
+            vault_id: 0,
+            bump,
+            lp_mint,
+            lp_decimals,
+            treasury: Pubkey::default(),
+            surplus_policy: SurplusPolicy::Yield,
+            wrap_sol: false,
+            guardian: Pubkey::default(),
+            paused: PauseFlags::default(),
+            pending_authority: Pubkey::default(),
+            fee_manager: authority,
+            asset_manager: authority,
+            timelocked: false,
+            sol_outflow: OutflowLimit::default(),
+            exit_mode: true,
+            compliance: Pubkey::default(),
+            reserve_count: 0,
+        })
+    }
+
+    // Surplus credited as yield reprices every LP token. Left to anyone, a first depositor could
+    // donate to the vault and sync to round the next deposits down to almost nothing.
+    pub fn check_yield_signer(&self, signer: &Pubkey) -> Result<()> {
+        if *signer != self.authority && *signer != self.fee_manager {
+            return Err(Errors::UnauthorizedYieldSync.into());
+        }
+        Ok(())
+    }
+
+    pub fn open_reserve(&mut self) -> Result<()> {
+        self.reserve_count = self.reserve_count
+            .checked_add(1)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(())
+    }
+
+    // Hand the vault to the pending authority. The fee and asset manager roles move with it,
+    // as `init_vault` gives them to the creator, so the previous authority keeps none of them.
+    pub fn accept_authority(&mut self) {
+        self.authority = self.pending_authority;
+        self.pending_authority = Pubkey::default();
+        self.fee_manager = self.authority;
+        self.asset_manager = self.authority;
+    }
+
+    pub fn set_role(&mut self, role: VaultRole, key: Pubkey) {
+        match role {
+            VaultRole::Guardian => self.guardian = key,
+            VaultRole::FeeManager => self.fee_manager = key,
+            VaultRole::AssetManager => self.asset_manager = key,
+            VaultRole::Compliance => self.compliance = key,
+        }
+    }
+
+    pub fn set_surplus_policy(&mut self, policy: SurplusPolicy, treasury: Pubkey) -> Result<()> {
+        if policy == SurplusPolicy::Treasury && treasury == Pubkey::default() {
+            return Err(Errors::TreasuryNotSet.into());
+        }
+        self.surplus_policy = policy;
+        self.treasury = treasury;
+        Ok(())
+    }
+}
+
+// Roles the vault authority hands out with `set_role`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VaultRole {
+    Guardian,     // Pauses and resumes operations
+    FeeManager,   // Sets the surplus policy and treasury
+    AssetManager, // Adds, updates and removes registered assets
+    Compliance,   // Freezes and thaws LP token accounts
+}
+
+// Operations of a vault that are currently stopped
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PauseFlags {
+    pub deposits: bool, // SOL, wrapped SOL and SPL deposits
+    pub sol_withdrawals: bool,
+    pub spl_withdrawals: bool,
+    pub lp_mint_creation: bool,
+}
+
+impl PauseFlags {
+    // 4 bools
+    pub const SIZE: usize = 4;
+}
+
+// Caps how much of one asset can leave the vault within any `window` seconds. Outflow is
+// counted in buckets of a quarter window, and a withdrawal is checked against every bucket
+// that overlaps the last window, so it counts for between one and one and a quarter windows.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct OutflowLimit {
+    pub window: i64,       // Seconds
+    pub limit: u64,        // 0 when there is no limit
+    pub outflow: [u64; 5], // Outflow of the last 5 buckets, at `bucket` modulo 5
+    pub bucket: i64,       // Latest bucket with recorded outflow
+    pub raised_limit: u64, // Set by the guardian, applies until `raised_until`
+    pub raised_until: i64,
+}
+
+impl OutflowLimit {
+    pub const BUCKETS_PER_WINDOW: i64 = 4;
+    // 2 u64 + 3 i64 + 5 u64 buckets
+    pub const SIZE: usize = 8 * 5 + 8 * 5;
+
+    pub fn set(&mut self, window: i64, limit: u64, now: i64) -> Result<()> {
+        if limit > 0 && window <= 0 {
+            return Err(Errors::InvalidOutflowLimit.into());
+        }
+        // Outflow that still counts under the old window carries over to the current bucket
+        let carried = self.current_outflow(now);
+        self.window = window;
+        self.limit = limit;
+        self.raised_limit = 0;
+        self.raised_until = 0;
+        self.outflow = [0; 5];
+        self.bucket = 0;
+        // Outflow is not tracked without a limit
+        if limit > 0 {
+            self.bucket = self.bucket_at(now);
+            self.outflow[Self::slot(self.bucket)] = carried;
+        }
+        Ok(())
+    }
+
+    pub fn raise(&mut self, limit: u64, duration: i64, now: i64) -> Result<()> {
+        if self.limit == 0 || limit <= self.limit || duration <= 0 || duration > self.window {
+            return Err(Errors::InvalidOutflowLimit.into());
+        }
+        self.raised_limit = limit;
+        self.raised_until = now.checked_add(duration).ok_or(Errors::NumericalOverflow)?;
+        Ok(())
+    }
+
+    // Outflow of the buckets that overlap the window ending at `now`
+    pub fn current_outflow(&self, now: i64) -> u64 {
+        if self.window <= 0 {
+            return 0;
+        }
+        let oldest = self.bucket_at(now) - Self::BUCKETS_PER_WINDOW;
+        (0..self.outflow.len() as i64)
+            .map(|age| self.bucket - age)
+            .filter(|bucket| *bucket >= oldest)
+            .fold(0u64, |total, bucket| total.saturating_add(self.outflow[Self::slot(bucket)]))
+    }
+
+    pub fn record(&mut self, amount: u64, now: i64) -> Result<()> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let outflow = self.current_outflow(now)
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        let limit = if now < self.raised_until { self.raised_limit } else { self.limit };
+        if outflow > limit {
+            return Err(Errors::OutflowLimitExceeded.into());
+        }
+
+        let bucket = self.bucket_at(now);
+        if bucket > self.bucket {
+            // Clear the slots of the buckets passed since the last withdrawal
+            let passed = (bucket - self.bucket).min(self.outflow.len() as i64);
+            for age in 0..passed {
+                self.outflow[Self::slot(bucket - age)] = 0;
+            }
+            self.bucket = bucket;
+        }
+        let slot = Self::slot(self.bucket);
+        self.outflow[slot] = self.outflow[slot]
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(())
+    }
+
+    fn bucket_at(&self, now: i64) -> i64 {
+        (now as i128 * Self::BUCKETS_PER_WINDOW as i128).div_euclid(self.window as i128) as i64
+    }
+
+    fn slot(bucket: i64) -> usize {
+        bucket.rem_euclid(5) as usize
+    }
+}
+
+// Where `sync_balance` sends funds the vault holds beyond its recorded balances
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SurplusPolicy {
+    Yield,    // Credit the surplus to LP holders
+    Treasury, // Move the surplus to the vault's treasury
+}
+
+// Bookkeeping for one SPL asset held by the vault
+#[account]
+pub struct AssetReserve {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub vault: Pubkey,
+    pub asset_mint: Pubkey,
+    pub lp_mint: Pubkey,
+    pub balance: u64, // Tokens the vault should hold for this asset
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub total_lp_minted: u64,
+    pub total_lp_burned: u64,
+    pub bump: u8,
+    pub outflow: OutflowLimit,
+}
+
+impl AssetReserve {
+    // Discriminator + version + 3 Pubkeys + 5 u64 + bump + outflow limit + reserved space
+    pub const SIZE: usize = 8 + 1 + 32 * 3 + 8 * 5 + 1 + OutflowLimit::SIZE + RESERVED_SPACE;
+
+    pub fn record_deposit(&mut self, amount: u64, lp_amount: u64) -> Result<()> {
+        self.balance = self.balance
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        self.total_deposited = self.total_deposited
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        self.total_lp_minted = self.total_lp_minted
+            .checked_add(lp_amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(())
+    }
+
+    // `reserve` is the reserve PDA of an asset, which may not have been created yet
+    pub fn check_empty(reserve: &AccountInfo) -> Result<()> {
+        if !reserve.data_is_empty() {
+            let reserve = AssetReserve::try_deserialize(&mut &reserve.data.borrow()[..])?;
+            if reserve.balance > 0 {
+                return Err(Errors::ReserveNotEmpty.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_withdraw(&mut self, amount: u64, lp_amount: u64) -> Result<()> {
+        self.balance = self.balance
+            .checked_sub(amount)
+            .ok_or(Errors::InsufficientBalance)?;
+        self.total_withdrawn = self.total_withdrawn
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        self.total_lp_burned = self.total_lp_burned
+            .checked_add(lp_amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        Ok(())
+    }
+}
+
+// Deposits and LP tokens of one owner in one vault
+#[account]
+pub struct UserPosition {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub vault: Pubkey,
+    pub owner: Pubkey,
+    pub approved_recipient: Pubkey, // Default Pubkey when only the owner can receive withdrawals
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub bump: u8,
+    pub assets: Vec<PositionAsset>,
+}
+
+impl UserPosition {
+    // SOL plus every registrable SPL asset
+    pub const MAX_ASSETS: usize = AssetRegistry::MAX_ASSETS + 1;
+    // Discriminator + version + 3 Pubkeys + 2 i64 + bump + Vec prefix + entries + reserved space
+    pub const SIZE: usize =
+        8 + 1 + 32 * 3 + 8 * 2 + 1 + 4 + PositionAsset::SIZE * Self::MAX_ASSETS + RESERVED_SPACE;
+
+    // Fill in a position just created by `init_if_needed`. Existing positions are left as is.
+    pub fn open(&mut self, vault: Pubkey, owner: Pubkey, bump: u8, now: i64) {
+        if self.owner == Pubkey::default() {
+            self.version = ACCOUNT_VERSION;
+            self.vault = vault;
+            self.owner = owner;
+            self.created_at = now;
+            self.bump = bump;
+        }
+    }
+
+    pub fn check_recipient(&self, recipient: &Pubkey) -> Result<()> {
+        let approved = self.approved_recipient != Pubkey::default()
+            && *recipient == self.approved_recipient;
+        if *recipient != self.owner && !approved {
+            return Err(Errors::UnapprovedRecipient.into());
+        }
+        Ok(())
+    }
+
+    pub fn record_deposit(&mut self, asset_mint: Pubkey, amount: u64, lp_amount: u64, now: i64) -> Result<()> {
+        let index = match self.assets.iter().position(|asset| asset.asset_mint == asset_mint) {
+            Some(index) => index,
+            None => {
+                if self.assets.len() >= Self::MAX_ASSETS {
+                    return Err(Errors::PositionFull.into());
+                }
+                self.assets.push(PositionAsset { asset_mint, principal: 0, lp_amount: 0 });
+                self.assets.len() - 1
+            }
+        };
+
+        let asset = &mut self.assets[index];
+        asset.principal = asset.principal
+            .checked_add(amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        asset.lp_amount = asset.lp_amount
+            .checked_add(lp_amount)
+            .ok_or(Errors::NumericalOverflow)?;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    // LP tokens can be transferred in, so withdrawals may exceed what the position recorded
+    pub fn record_withdraw(&mut self, asset_mint: Pubkey, amount: u64, lp_amount: u64, now: i64) {
+        if let Some(asset) = self.assets.iter_mut().find(|asset| asset.asset_mint == asset_mint) {
+            asset.principal = asset.principal.saturating_sub(amount);
+            asset.lp_amount = asset.lp_amount.saturating_sub(lp_amount);
+        }
+        self.updated_at = now;
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct PositionAsset {
+    pub asset_mint: Pubkey, // The native mint for SOL
+    pub principal: u64,
+    pub lp_amount: u64,
+}
+
+impl PositionAsset {
+    // Pubkey + 2 u64
+    pub const SIZE: usize = 32 + 8 * 2;
+}
+
+// SPL mints accepted by a vault, managed by the vault authority
+#[account]
+pub struct AssetRegistry {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub assets: Vec<AssetConfig>,
+}
+
+impl AssetRegistry {
+    pub const MAX_ASSETS: usize = 16;
+    // Discriminator + version + Pubkey + bump + Vec prefix + entries + reserved space
+    pub const SIZE: usize = 8 + 1 + 32 + 1 + 4 + AssetConfig::SIZE * Self::MAX_ASSETS + RESERVED_SPACE;
+
+    pub fn find(&self, mint: &Pubkey) -> Option<&AssetConfig> {
+        self.assets.iter().find(|asset| asset.mint == *mint)
+    }
+
+    pub fn find_mut(&mut self, mint: &Pubkey) -> Option<&mut AssetConfig> {
+        self.assets.iter_mut().find(|asset| asset.mint == *mint)
+    }
+
+    // `lp_mint` is the LP mint PDA of the asset. It outlives a removal of the asset, so a
+    // re-added asset is bound to it again instead of waiting for `init_token`.
+    pub fn add(
+        &mut self,
+        mint: Pubkey,
+        asset_decimals: u8,
+        params: &AssetParams,
+        lp_mint: &AccountInfo,
+    ) -> Result<()> {
+        if self.find(&mint).is_some() {
+            return Err(Errors::AssetAlreadyRegistered.into());
+        }
+        if self.assets.len() >= Self::MAX_ASSETS {
+            return Err(Errors::RegistryFull.into());
+        }
+
+        let (lp_mint_key, lp_decimals) = if lp_mint.data_is_empty() {
+            (Pubkey::default(), 0) // Set by `init_token`
+        } else {
+            let existing = Mint::try_deserialize(&mut &lp_mint.data.borrow()[..])?;
+            (lp_mint.key(), existing.decimals)
+        };
+
+        self.assets.push(AssetConfig {
+            mint,
+            lp_mint: lp_mint_key,
+            lp_decimals,
+            asset_decimals,
+            enabled: params.enabled,
+            deposit_cap: params.deposit_cap,
+            min_deposit: params.min_deposit,
+        });
+        Ok(())
+    }
+
+    pub fn update(&mut self, mint: &Pubkey, params: &AssetParams) -> Result<()> {
+        let config = self.find_mut(mint).ok_or(Errors::AssetNotRegistered)?;
+        config.enabled = params.enabled;
+        config.deposit_cap = params.deposit_cap;
+        config.min_deposit = params.min_deposit;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, mint: &Pubkey) -> Result<()> {
+        let index = self.assets
+            .iter()
+            .position(|asset| asset.mint == *mint)
+            .ok_or(Errors::AssetNotRegistered)?;
+        self.assets.remove(index);
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct AssetConfig {
+    pub mint: Pubkey,
+    pub lp_mint: Pubkey, // Set by `init_token`
+    pub lp_decimals: u8,
+    pub asset_decimals: u8, // Decimals of `mint`, recorded by `add_asset`
+    pub enabled: bool,
+    pub deposit_cap: u64, // Maximum reserve balance, 0 for no cap
+    pub min_deposit: u64,
+}
+
+impl AssetConfig {
+    // 2 Pubkeys + 2 decimals + bool + 2 u64
+    pub const SIZE: usize = 32 * 2 + 1 + 1 + 1 + 8 * 2;
+}
+
+#[derive(Accounts)]
+#[instruction(params: InitTokenParams)]
+pub struct InitToken<'info> {
+    /// New Metaplex Account being created
+    #[account(mut)]
+    pub metadata: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    // The LP mint is created under the token program of its asset
+    #[account(mint::token_program = token_program)]
+    pub asset_mint: InterfaceAccount<'info, Mint>, // Deposited asset, the native mint for SOL
+    #[account(
+        init,
+        seeds = [b"mint", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump,
+        payer = payer,
+        mint::decimals = params.decimals,
+        mint::authority = mint,
+        mint::freeze_authority = mint,
+        mint::token_program = token_program,
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub token_metadata_program: Program<'info, Metaplex>,
+    // Only needed when `params.collection` is set
+    pub collection_authority: Option<Signer<'info>>,
+    /// CHECK: Compared against `params.collection` and the collection metadata
+    pub collection_mint: Option<UncheckedAccount<'info>>,
+    #[account(mut)]
+    pub collection_metadata: Option<Box<Account<'info, MetadataAccount>>>,
+    /// CHECK: Validated by the token metadata program
+    pub collection_master_edition: Option<UncheckedAccount<'info>>,
+}
+
+// Define the init token params
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct InitTokenParams {
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub decimals: u8,
+    pub seller_fee_basis_points: u16,
+    pub creators: Option<Vec<CreatorParams>>,
+    pub collection: Option<Pubkey>, // Collection mint, verified when the token is created
+    pub freezable: bool,            // Keep the mint PDA as freeze authority, for the compliance role
+}
+
+impl InitTokenParams {
+    // Metaplex allows at most 5 creators
+    pub const MAX_CREATORS: usize = 5;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.seller_fee_basis_points > 10_000 {
+            return Err(Errors::InvalidSellerFee.into());
+        }
+
+        if let Some(creators) = &self.creators {
+            if creators.len() > Self::MAX_CREATORS {
+                return Err(Errors::TooManyCreators.into());
+            }
+            for (index, creator) in creators.iter().enumerate() {
+                if creators[..index].iter().any(|other| other.address == creator.address) {
+                    return Err(Errors::DuplicateCreator.into());
+                }
+            }
+            let total_share: u16 = creators.iter().map(|creator| creator.share as u16).sum();
+            if total_share != 100 {
+                return Err(Errors::InvalidCreatorShares.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct CreatorParams {
+    pub address: Pubkey,
+    pub share: u8, // Percentage of royalties, all shares must add up to 100
+}
+
+#[derive(Accounts)]
+pub struct UpdateTokenMetadata<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>, // Deposited asset, the native mint for SOL
+    #[account(
+        seeds = [b"mint", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), mint.key().as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub metadata: Box<Account<'info, MetadataAccount>>,
+    pub token_metadata_program: Program<'info, Metaplex>,
+}
+
+// Fields left as `None` are not changed
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct UpdateTokenMetadataParams {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub uri: Option<String>,
+    pub new_update_authority: Option<Pubkey>,
+    pub is_mutable: Option<bool>, // `Some(false)` makes the metadata immutable
+}
+
+// Withdraw Context
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    /// CHECK: Receives the SOL, must be the owner or their approved recipient
+    #[account(mut)]
+    pub user: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    // Created for LP tokens received by transfer
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault_account.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    #[account(
+        mut,
+        seeds = [b"mint", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // SOL LP token mint
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = owner,
+        associated_token::token_program = token_program,
+    )]
+    pub source: InterfaceAccount<'info, TokenAccount>, // Owner's LP token account
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+// Settings of a registered SPL mint
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct AssetParams {
+    pub enabled: bool,
+    pub deposit_cap: u64,
+    pub min_deposit: u64,
+}
+
+#[derive(Accounts)]
+pub struct AddAsset<'info> {
+    pub asset_manager: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = asset_manager,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    /// CHECK: LP mint PDA of the asset, left over if the asset was registered before
+    #[account(
+        seeds = [b"mint", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub lp_mint: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManageAsset<'info> {
+    pub asset_manager: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = asset_manager,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateVault<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    pub new_authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        constraint = vault_account.pending_authority == new_authority.key() @ Errors::NotPendingAuthority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFees<'info> {
+    pub fee_manager: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = fee_manager,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+}
+
+// Signed by the vault authority or its guardian
+#[derive(Accounts)]
+pub struct SetPause<'info> {
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+}
+
+#[event]
+pub struct PauseUpdated {
+    pub vault: Pubkey,
+    pub paused: PauseFlags,
+    pub updated_by: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct SetOutflowLimit<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    // Reserve of the limited SPL asset, none for SOL
+    #[account(
+        mut,
+        seeds = [b"reserve", vault_account.key().as_ref(), reserve.asset_mint.as_ref()],
+        bump = reserve.bump
+    )]
+    pub reserve: Option<Box<Account<'info, AssetReserve>>>,
+}
+
+// Signed by the vault authority or its guardian
+#[derive(Accounts)]
+pub struct RaiseOutflowLimit<'info> {
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    // Reserve of the limited SPL asset, none for SOL
+    #[account(
+        mut,
+        seeds = [b"reserve", vault_account.key().as_ref(), reserve.asset_mint.as_ref()],
+        bump = reserve.bump
+    )]
+    pub reserve: Option<Box<Account<'info, AssetReserve>>>,
+}
+
+#[event]
+pub struct ExitModeEnabled {
+    pub vault: Pubkey,
+    pub enabled_by: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct ExitRedeem<'info> {
+    /// CHECK: Receives the assets, must be the owner or their approved recipient
+    #[account(mut)]
+    pub user: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault.creator.as_ref(),
+            vault.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    pub system_program: Program<'info, System>,
+    // Only needed to redeem SOL held as lamports
+    #[account(
+        mut,
+        seeds = [b"mint", vault.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: Option<InterfaceAccount<'info, Mint>>, // SOL LP token mint
+    #[account(mut)]
+    pub source: Option<InterfaceAccount<'info, TokenAccount>>, // Owner's SOL LP token account
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseVault<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+        close = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump,
+        close = authority,
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    // SOL LP mint, needed once `init_token` has set it up
+    #[account(
+        seeds = [b"mint", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+    // Closed with the vault when they exist
+    #[account(
+        mut,
+        seeds = [b"multisig".as_ref(), vault_account.key().as_ref()],
+        bump = multisig.bump,
+        close = authority,
+    )]
+    pub multisig: Option<Box<Account<'info, Multisig>>>,
+    #[account(
+        mut,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
+        bump = timelock.bump,
+        close = authority,
+    )]
+    pub timelock: Option<Box<Account<'info, Timelock>>>,
+}
+
+#[event]
+pub struct VaultClosed {
+    pub vault: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct FreezeLpAccount<'info> {
+    pub compliance: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = compliance,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>, // Asset of the LP mint, the native mint for SOL
+    #[account(
+        seeds = [b"mint", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // LP token mint
+    #[account(
+        mut,
+        token::mint = mint,
+        token::token_program = token_program,
+    )]
+    pub lp_account: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> FreezeLpAccount<'info> {
+    // LP mints created without `freezable` have no freeze authority
+    pub fn check_freezable(&self) -> Result<()> {
+        if self.mint.freeze_authority != Some(self.mint.key()).into() {
+            return Err(Errors::LpMintNotFreezable.into());
+        }
+        Ok(())
+    }
+}
+
+#[event]
+pub struct LpAccountFreezeUpdated {
+    pub vault: Pubkey,
+    pub lp_mint: Pubkey,
+    pub account: Pubkey,
+    pub owner: Pubkey,
+    pub frozen: bool,
+    pub updated_by: Pubkey,
+}
+
+#[event]
+pub struct OutflowLimitUpdated {
+    pub vault: Pubkey,
+    pub asset_mint: Pubkey, // Native mint for SOL
+    pub outflow: OutflowLimit,
+    pub updated_by: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct SyncBalance<'info> {
+    pub signer: Signer<'info>, // Must be the authority or fee manager to credit surplus as yield
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    /// CHECK: Must be the vault's treasury, only needed under `SurplusPolicy::Treasury`
+    #[account(mut)]
+    pub treasury: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct SyncBalanceSpl<'info> {
+    pub signer: Signer<'info>, // Must be the authority or fee manager to credit surplus as yield
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault.creator.as_ref(),
+            vault.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"reserve", vault.key().as_ref(), asset_mint.key().as_ref()],
+        bump = reserve.bump
+    )]
+    pub reserve: Box<Account<'info, AssetReserve>>,
+    #[account(
+        mut,
+        associated_token::mint = asset_mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    // Only needed under `SurplusPolicy::Treasury`
+    #[account(
+        mut,
+        token::mint = asset_mint,
+        token::authority = vault.treasury,
+        token::token_program = token_program,
+    )]
+    pub treasury_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct BalanceSynced {
+    pub vault: Pubkey,
+    pub asset_mint: Pubkey, // The native mint for SOL
+    pub recorded: u64,
+    pub actual: u64,
+    pub surplus: u64,
+    pub policy: SurplusPolicy,
+}
+
+#[derive(Accounts)]
+pub struct SetWithdrawRecipient<'info> {
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"position", position.vault.as_ref(), owner.key().as_ref()],
+        bump = position.bump,
+        has_one = owner,
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveAsset<'info> {
+    pub asset_manager: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = asset_manager,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    /// CHECK: Reserve PDA of the asset, which may not have been created yet
+    #[account(
+        seeds = [b"reserve", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub reserve: UncheckedAccount<'info>,
+}
+
+#[error_code]
+pub enum Errors {
+    #[msg("Insufficient balance in the vault.")]
+    InsufficientBalance,
+    #[msg("Withdrawal would leave the vault below its rent-exempt minimum.")]
+    InsufficientLiquidity,
+    #[msg("Numerical overflow occurred.")]
+    NumericalOverflow,
+    #[msg("Amount is too small to mint or burn any LP tokens.")]
+    ZeroShares,
+    #[msg("Vault has outstanding LP tokens but no assets.")]
+    InvalidSharePrice,
+    #[msg("Asset is not registered in the vault.")]
+    AssetNotRegistered,
+    #[msg("Asset is already registered in the vault.")]
+    AssetAlreadyRegistered,
+    #[msg("Deposits of this asset are disabled.")]
+    AssetDisabled,
+    #[msg("Asset registry is full.")]
+    RegistryFull,
+    #[msg("Deposit is below the asset's minimum.")]
+    DepositBelowMinimum,
+    #[msg("Deposit would exceed the asset's cap.")]
+    DepositCapExceeded,
+    #[msg("Vault still holds this asset.")]
+    ReserveNotEmpty,
+    #[msg("Recipient is not the position owner or their approved recipient.")]
+    UnapprovedRecipient,
+    #[msg("Position already tracks the maximum number of assets.")]
+    PositionFull,
+    #[msg("LP mint of this asset has not been set up by the vault authority.")]
+    LpMintNotInitialized,
+    #[msg("Seller fee basis points cannot exceed 10000.")]
+    InvalidSellerFee,
+    #[msg("Creator shares must add up to 100.")]
+    InvalidCreatorShares,
+    #[msg("Too many creators, the maximum is 5.")]
+    TooManyCreators,
+    #[msg("Creator is listed more than once.")]
+    DuplicateCreator,
+    #[msg("Collection authority, mint, metadata and master edition accounts are required.")]
+    MissingCollectionAccounts,
+    #[msg("Collection accounts do not match the requested collection.")]
+    CollectionMismatch,
+    #[msg("Vault treasury is not set.")]
+    TreasuryNotSet,
+    #[msg("Account is not the vault treasury.")]
+    InvalidTreasury,
+    #[msg("SOL deposits of this vault are wrapped, use the wrapped SOL instructions.")]
+    WrappedSolEnabled,
+    #[msg("SOL deposits of this vault are not wrapped.")]
+    WrappedSolDisabled,
+    #[msg("Vault still has SOL deposits or SOL LP tokens outstanding.")]
+    SolDepositsOutstanding,
+    #[msg("Only the vault authority or guardian can pause the vault.")]
+    UnauthorizedPauser,
+    #[msg("Deposits to this vault are paused.")]
+    DepositsPaused,
+    #[msg("SOL withdrawals from this vault are paused.")]
+    SolWithdrawalsPaused,
+    #[msg("SPL withdrawals from this vault are paused.")]
+    SplWithdrawalsPaused,
+    #[msg("LP mint creation for this vault is paused.")]
+    LpMintCreationPaused,
+    #[msg("Signer is not the proposed vault authority.")]
+    NotPendingAuthority,
+    #[msg("Multisig threshold must be between 1 and the number of signers.")]
+    InvalidThreshold,
+    #[msg("Too many multisig signers, the maximum is 10.")]
+    TooManySigners,
+    #[msg("Multisig signer is listed more than once.")]
+    DuplicateSigner,
+    #[msg("Signer is not a member of the multisig.")]
+    NotMultisigSigner,
+    #[msg("Signer has already approved this proposal.")]
+    AlreadyApproved,
+    #[msg("Proposal has already been executed.")]
+    ProposalAlreadyExecuted,
+    #[msg("Multisig signers changed after this proposal was created.")]
+    StaleProposal,
+    #[msg("Proposal does not have enough approvals.")]
+    NotEnoughApprovals,
+    #[msg("Multisig does not hold the vault role this action needs.")]
+    MultisigNotAuthorized,
+    #[msg("Accounts needed to execute this proposal are missing.")]
+    MissingProposalAccounts,
+    #[msg("Accounts passed do not match the proposal.")]
+    ProposalAccountMismatch,
+    #[msg("Timelock delay must be positive.")]
+    InvalidTimelockDelay,
+    #[msg("Vault changes must go through the timelock.")]
+    TimelockRequired,
+    #[msg("Signer does not hold the role this change needs.")]
+    UnauthorizedChange,
+    #[msg("Timelock queue is full.")]
+    TimelockQueueFull,
+    #[msg("Change is not queued.")]
+    ChangeNotQueued,
+    #[msg("Timelock delay of this change has not passed yet.")]
+    TimelockNotExpired,
+    #[msg("Outflow limit needs a positive window and a raise must exceed it for at most one window.")]
+    InvalidOutflowLimit,
+    #[msg("Only the authority or guardian can raise an outflow limit.")]
+    UnauthorizedLimitRaise,
+    #[msg("Withdrawal exceeds the outflow limit of the asset.")]
+    OutflowLimitExceeded,
+    #[msg("Vault is in exit mode.")]
+    ExitModeActive,
+    #[msg("Vault is not in exit mode.")]
+    ExitModeInactive,
+    #[msg("Exit redemption needs the full set of accounts for each asset.")]
+    MissingExitAccounts,
+    #[msg("Accounts passed do not match the redeemed asset.")]
+    ExitAccountMismatch,
+    #[msg("Account cannot be migrated by this authority.")]
+    InvalidMigrationAccount,
+    #[msg("Account layout version is not supported.")]
+    UnsupportedAccountVersion,
+    #[msg("Assets must be removed from the registry before closing the vault.")]
+    AssetsStillRegistered,
+    #[msg("Vault still holds assets or has LP tokens outstanding.")]
+    VaultNotEmpty,
+    #[msg("Accounts passed do not match the closed vault.")]
+    InvalidCloseAccount,
+    #[msg("LP mint has no freeze authority.")]
+    LpMintNotFreezable,
+    #[msg("LP token account is frozen.")]
+    LpAccountFrozen,
+    #[msg("Every reserve of the vault must be closed with it.")]
+    ReservesStillOpen,
+    #[msg("Only the authority or fee manager can credit surplus as yield.")]
+    UnauthorizedYieldSync,
+    #[msg("The multisig cannot become the vault authority.")]
+    MultisigCannotBeAuthority,
+}
+
+#[derive(Accounts)]
+pub struct DepositSpl<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub asset_mint: InterfaceAccount<'info, Mint>, // Deposited SPL token mint
+    #[account(
+        mut,
+        token::mint = asset_mint,
+        token::authority = user,
+        token::token_program = token_program,
+    )]
+    pub user_ata: InterfaceAccount<'info, TokenAccount>, // For SPL
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Box<Account<'info, AssetRegistry>>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = asset_mint,
+        associated_token::authority = vault_account,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>, // Vault's ATA for the asset
+    #[account(
+        mut,
+        seeds = [b"mint", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // LP token mint for the deposited asset
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = AssetReserve::SIZE,
+        seeds = [b"reserve", vault_account.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub reserve: Box<Account<'info, AssetReserve>>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = mint,
+        associated_token::authority = user,
+        associated_token::token_program = token_program,
+    )]
+    pub user_lp_ata: InterfaceAccount<'info, TokenAccount>, // User LP ATA
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault_account.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSpl<'info> {
+    /// CHECK: Receives the tokens, must be the owner or their approved recipient
+    #[account(mut)]
+    pub user: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault.creator.as_ref(),
+            vault.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    // Created for LP tokens received by transfer
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    pub asset_mint: InterfaceAccount<'info, Mint>, // Withdrawn SPL token mint
+    #[account(
+        mut,
+        token::mint = asset_mint,
+        token::authority = user,
+        token::token_program = token_program,
+    )]
+    pub user_ata: InterfaceAccount<'info, TokenAccount>, // To
+    #[account(
+        mut,
+        associated_token::mint = asset_mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>, // From the vault's ATA for the asset
+    #[account(
+        mut,
+        seeds = [b"mint", vault.key().as_ref(), asset_mint.key().as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // LP token mint for the withdrawn asset
+    #[account(
+        mut,
+        seeds = [b"reserve", vault.key().as_ref(), asset_mint.key().as_ref()],
+        bump = reserve.bump
+    )]
+    pub reserve: Box<Account<'info, AssetReserve>>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = owner,
+        associated_token::token_program = token_program,
+    )]
+    pub user_lp_ata: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetSolWrapping<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        seeds = [b"mint", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // SOL LP token mint
+}
+
+#[derive(Accounts)]
+pub struct DepositWrappedSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(address = native_mint::ID)]
+    pub native_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = native_mint,
+        associated_token::authority = vault_account,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>, // Vault's wrapped SOL account
+    #[account(
+        mut,
+        seeds = [b"mint", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // SOL LP token mint
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = AssetReserve::SIZE,
+        seeds = [b"reserve", vault_account.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub reserve: Box<Account<'info, AssetReserve>>,
+    #[account(
+        init_if_needed,
         payer = user,
         associated_token::mint = mint,
         associated_token::authority = user,
+        associated_token::token_program = token_program,
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault_account.key().as_ref(), user.key().as_ref()],
+        bump
     )]
-    pub user_lp_ata: Account<'info, TokenAccount>, // User LP ATA
+    pub position: Box<Account<'info, UserPosition>>,
     pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawSpl<'info> {
+pub struct WithdrawWrappedSol<'info> {
+    /// CHECK: Receives the SOL, must be the owner or their approved recipient
     #[account(mut)]
     pub user: AccountInfo<'info>,
     #[account(
         mut,
-        seeds = [b"myvault".as_ref()],
-        bump
+        seeds = [
+            b"myvault".as_ref(),
+            vault.creator.as_ref(),
+            vault.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault.bump
     )]
     pub vault: Box<Account<'info, Vault>>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    // Created for LP tokens received by transfer
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SIZE,
+        seeds = [b"position", vault.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, UserPosition>>,
+    #[account(address = native_mint::ID)]
+    pub native_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = native_mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program,
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>, // Vault's wrapped SOL account
+    // Holds the withdrawn wrapped SOL until it is closed, within this instruction
+    #[account(
+        init,
+        payer = owner,
+        seeds = [b"unwrap", vault.key().as_ref(), owner.key().as_ref()],
+        bump,
+        token::mint = native_mint,
+        token::authority = vault,
+        token::token_program = token_program,
+    )]
+    pub unwrap_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"mint", vault.key().as_ref(), native_mint::ID.as_ref()],
+        bump
+    )]
+    pub mint: InterfaceAccount<'info, Mint>, // SOL LP token mint
+    #[account(
+        mut,
+        seeds = [b"reserve", vault.key().as_ref(), native_mint::ID.as_ref()],
+        bump = reserve.bump
+    )]
+    pub reserve: Box<Account<'info, AssetReserve>>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = owner,
+        associated_token::token_program = token_program,
+    )]
+    pub source: InterfaceAccount<'info, TokenAccount>, // Owner's LP token account
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// M-of-N signers that can act for a vault role through proposals, derived from `[b"multisig", vault]`
+#[account]
+pub struct Multisig {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub vault: Pubkey,
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+    pub proposal_count: u64, // Seed of the next proposal
+    pub signers_version: u32, // Bumped whenever the signers change
+    pub bump: u8,
+}
+
+impl Multisig {
+    pub const MAX_SIGNERS: usize = 10;
+    // Discriminator + version + Pubkey + Vec prefix + signers + threshold + u64 + u32 + bump
+    // + reserved space
+    pub const SIZE: usize = 8 + 1 + 32 + 4 + 32 * Self::MAX_SIGNERS + 1 + 8 + 4 + 1 + RESERVED_SPACE;
+
+    pub fn validate(signers: &[Pubkey], threshold: u8) -> Result<()> {
+        if signers.len() > Self::MAX_SIGNERS {
+            return Err(Errors::TooManySigners.into());
+        }
+        if threshold == 0 || threshold as usize > signers.len() {
+            return Err(Errors::InvalidThreshold.into());
+        }
+        for (index, signer) in signers.iter().enumerate() {
+            if signers[..index].contains(signer) {
+                return Err(Errors::DuplicateSigner.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn signer_index(&self, key: &Pubkey) -> Result<usize> {
+        self.signers
+            .iter()
+            .position(|signer| signer == key)
+            .ok_or(Errors::NotMultisigSigner.into())
+    }
+}
+
+// An admin action waiting for multisig approvals, derived from `[b"proposal", multisig, id]`
+#[account]
+pub struct Proposal {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub multisig: Pubkey,
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub action: MultisigAction,
+    pub approvals: Vec<bool>, // One entry per multisig signer
+    pub executed: bool,
+    pub signers_version: u32,
+    pub created_at: i64,
+    pub bump: u8,
+}
+
+impl Proposal {
+    // Discriminator + version + Pubkey + u64 + Pubkey + action + Vec prefix + approvals + bool
+    // + u32 + i64 + bump + reserved space
+    pub const SIZE: usize = 8 + 1 + 32 + 8 + 32 + MultisigAction::MAX_SIZE + 4 + Multisig::MAX_SIGNERS
+        + 1 + 4 + 8 + 1 + RESERVED_SPACE;
+
+    pub fn check_open(&self, multisig: &Multisig) -> Result<()> {
+        if self.executed {
+            return Err(Errors::ProposalAlreadyExecuted.into());
+        }
+        if self.signers_version != multisig.signers_version {
+            return Err(Errors::StaleProposal.into());
+        }
+        Ok(())
+    }
+}
+
+// Admin actions a multisig can propose, each needing the same vault role as its instruction
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub enum MultisigAction {
+    SetPause { paused: PauseFlags },
+    ApplyChange { change: ConfigChange }, // Only while the vault has no timelock
+    QueueChange { change: ConfigChange },
+    SetSigners { signers: Vec<Pubkey>, threshold: u8 },
+}
+
+impl MultisigAction {
+    // Variant + the largest payload, `SetSigners`
+    pub const MAX_SIZE: usize = 1 + 4 + 32 * Multisig::MAX_SIGNERS + 1;
+}
+
+#[derive(Accounts)]
+pub struct CreateMultisig<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        init,
+        payer = authority,
+        space = Multisig::SIZE,
+        seeds = [b"multisig".as_ref(), vault_account.key().as_ref()],
+        bump
+    )]
+    pub multisig: Box<Account<'info, Multisig>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
     #[account(mut)]
-    pub user_ata: Account<'info, TokenAccount>, // To
+    pub proposer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"multisig".as_ref(), multisig.vault.as_ref()],
+        bump = multisig.bump
+    )]
+    pub multisig: Box<Account<'info, Multisig>>,
+    #[account(
+        init,
+        payer = proposer,
+        space = Proposal::SIZE,
+        seeds = [b"proposal".as_ref(), multisig.key().as_ref(), multisig.proposal_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proposal: Box<Account<'info, Proposal>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveProposal<'info> {
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"multisig".as_ref(), multisig.vault.as_ref()],
+        bump = multisig.bump
+    )]
+    pub multisig: Box<Account<'info, Multisig>>,
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), multisig.key().as_ref(), proposal.id.to_le_bytes().as_ref()],
+        bump = proposal.bump,
+        has_one = multisig,
+    )]
+    pub proposal: Box<Account<'info, Proposal>>,
+}
+
+// Anyone can execute an approved proposal
+#[derive(Accounts)]
+pub struct ExecuteProposal<'info> {
+    #[account(
+        mut,
+        seeds = [b"multisig".as_ref(), vault_account.key().as_ref()],
+        bump = multisig.bump
+    )]
+    pub multisig: Box<Account<'info, Multisig>>,
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), multisig.key().as_ref(), proposal.id.to_le_bytes().as_ref()],
+        bump = proposal.bump,
+        has_one = multisig,
+    )]
+    pub proposal: Box<Account<'info, Proposal>>,
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    // Only needed by the asset actions
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
+    )]
+    pub registry: Option<Box<Account<'info, AssetRegistry>>>,
+    pub asset_mint: Option<InterfaceAccount<'info, Mint>>,
+    /// CHECK: LP mint PDA of the added asset, checked against the proposal
+    pub lp_mint: Option<UncheckedAccount<'info>>,
+    /// CHECK: Reserve PDA of the removed asset, checked against the proposal
+    pub reserve: Option<UncheckedAccount<'info>>,
+    // Only needed by the timelock actions
+    #[account(
+        mut,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
+        bump = timelock.bump
+    )]
+    pub timelock: Option<Box<Account<'info, Timelock>>>,
+}
+
+// Fee and asset changes, applied directly or through the timelock
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub enum ConfigChange {
+    SetSurplusPolicy { policy: SurplusPolicy, treasury: Pubkey },
+    AddAsset { mint: Pubkey, params: AssetParams },
+    UpdateAsset { mint: Pubkey, params: AssetParams },
+    RemoveAsset { mint: Pubkey },
+    SetTimelockDelay { delay: i64 },
+}
+
+impl ConfigChange {
+    // Variant + the largest payload, `AddAsset`
+    pub const MAX_SIZE: usize = 1 + 32 + 1 + 8 * 2;
+
+    // Key of the role that may make this change
+    pub fn role_holder(&self, vault: &Vault) -> Pubkey {
+        match self {
+            ConfigChange::SetSurplusPolicy { .. } => vault.fee_manager,
+            ConfigChange::AddAsset { .. }
+            | ConfigChange::UpdateAsset { .. }
+            | ConfigChange::RemoveAsset { .. } => vault.asset_manager,
+            ConfigChange::SetTimelockDelay { .. } => vault.authority,
+        }
+    }
+}
+
+// Pending changes of a vault and when they can be executed, derived from `[b"timelock", vault]`
+#[account]
+pub struct Timelock {
+    pub version: u8, // `ACCOUNT_VERSION` once created or migrated
+    pub vault: Pubkey,
+    pub delay: i64, // Seconds between queueing and execution
+    pub next_id: u64,
+    pub changes: Vec<QueuedChange>,
+    pub bump: u8,
+}
+
+impl Timelock {
+    pub const MAX_QUEUED: usize = 8;
+    // Discriminator + version + Pubkey + i64 + u64 + Vec prefix + entries + bump + reserved space
+    pub const SIZE: usize =
+        8 + 1 + 32 + 8 + 8 + 4 + QueuedChange::SIZE * Self::MAX_QUEUED + 1 + RESERVED_SPACE;
+
+    pub fn queue(&mut self, vault: Pubkey, change: ConfigChange, queued_by: Pubkey, now: i64) -> Result<()> {
+        if self.changes.len() >= Self::MAX_QUEUED {
+            return Err(Errors::TimelockQueueFull.into());
+        }
+
+        let id = self.next_id;
+        let eta = now.checked_add(self.delay).ok_or(Errors::NumericalOverflow)?;
+        self.next_id = id.checked_add(1).ok_or(Errors::NumericalOverflow)?;
+        emit!(ChangeQueued {
+            vault,
+            id,
+            change: change.clone(),
+            eta,
+        });
+        self.changes.push(QueuedChange { id, change, queued_by, queued_at: now, eta });
+
+        msg!("Change {} queued, executable from {}.", id, eta);
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, vault: Pubkey, id: u64, cancelled_by: Pubkey) -> Result<()> {
+        let index = self.changes
+            .iter()
+            .position(|queued| queued.id == id)
+            .ok_or(Errors::ChangeNotQueued)?;
+        self.changes.remove(index);
+
+        emit!(ChangeCancelled { vault, id, cancelled_by });
+        msg!("Change {} cancelled.", id);
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct QueuedChange {
+    pub id: u64,
+    pub change: ConfigChange,
+    pub queued_by: Pubkey,
+    pub queued_at: i64,
+    pub eta: i64, // Earliest execution time
+}
+
+impl QueuedChange {
+    // u64 + change + Pubkey + 2 i64
+    pub const SIZE: usize = 8 + ConfigChange::MAX_SIZE + 32 + 8 * 2;
+}
+
+#[event]
+pub struct ChangeQueued {
+    pub vault: Pubkey,
+    pub id: u64,
+    pub change: ConfigChange,
+    pub eta: i64,
+}
+
+#[event]
+pub struct ChangeCancelled {
+    pub vault: Pubkey,
+    pub id: u64,
+    pub cancelled_by: Pubkey,
+}
+
+#[event]
+pub struct ChangeExecuted {
+    pub vault: Pubkey,
+    pub id: u64,
+}
+
+#[derive(Accounts)]
+pub struct InitTimelock<'info> {
     #[account(mut)]
-    pub vault_ata: Account<'info, TokenAccount>, // From 
+    pub authority: Signer<'info>,
     #[account(
         mut,
-        seeds = [b"mint"],
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        init,
+        payer = authority,
+        space = Timelock::SIZE,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
         bump
     )]
-    pub mint: Account<'info, Mint>,
+    pub timelock: Box<Account<'info, Timelock>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct QueueChange<'info> {
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
     #[account(
         mut,
-        associated_token::mint = mint,
-        associated_token::authority = user,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
+        bump = timelock.bump
+    )]
+    pub timelock: Box<Account<'info, Timelock>>,
+}
+
+#[derive(Accounts)]
+pub struct CancelChange<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump,
+        has_one = authority,
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
+        bump = timelock.bump
+    )]
+    pub timelock: Box<Account<'info, Timelock>>,
+}
+
+// Anyone can execute a change whose delay has passed
+#[derive(Accounts)]
+pub struct ExecuteChange<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"myvault".as_ref(),
+            vault_account.creator.as_ref(),
+            vault_account.vault_id.to_le_bytes().as_ref(),
+        ],
+        bump = vault_account.bump
+    )]
+    pub vault_account: Box<Account<'info, Vault>>,
+    #[account(
+        mut,
+        seeds = [b"timelock".as_ref(), vault_account.key().as_ref()],
+        bump = timelock.bump
+    )]
+    pub timelock: Box<Account<'info, Timelock>>,
+    // Only needed by the asset changes
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref(), vault_account.key().as_ref()],
+        bump = registry.bump
     )]
-    pub user_lp_ata: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub registry: Option<Box<Account<'info, AssetRegistry>>>,
+    pub asset_mint: Option<InterfaceAccount<'info, Mint>>,
+    /// CHECK: LP mint PDA of the added asset, checked against the change
+    pub lp_mint: Option<UncheckedAccount<'info>>,
+    /// CHECK: Reserve PDA of the removed asset, checked against the change
+    pub reserve: Option<UncheckedAccount<'info>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fails_with<T: std::fmt::Debug + PartialEq>(result: Result<T>, error: Errors) {
+        assert_eq!(result, Err(error.into()));
+    }
+
+    #[test]
+    fn scale_amount_between_decimals() {
+        // Asset decimals below, above and equal to the LP decimals
+        assert_eq!(scale_amount(1_500_000, 6, 9).unwrap(), 1_500_000_000);
+        assert_eq!(
+            scale_amount(1_500_000_000_000, 12, 9).unwrap(),
+            1_500_000_000
+        );
+        assert_eq!(scale_amount(1_500_000_000, 9, 9).unwrap(), 1_500_000_000);
+        // Scaling down rounds toward zero
+        assert_eq!(scale_amount(1_999, 12, 9).unwrap(), 1);
+        assert_eq!(scale_amount(999, 12, 9).unwrap(), 0);
+    }
+
+    #[test]
+    fn scale_amount_overflow() {
+        assert_eq!(scale_amount(u64::MAX, 9, 9).unwrap(), u64::MAX);
+        fails_with(scale_amount(u64::MAX, 6, 9), Errors::NumericalOverflow);
+        fails_with(scale_amount(1, 0, 20), Errors::NumericalOverflow);
+        fails_with(scale_amount(1, 20, 0), Errors::NumericalOverflow);
+        assert_eq!(
+            scale_amount(u64::MAX, 19, 0).unwrap(),
+            u64::MAX / 10u64.pow(19)
+        );
+    }
+
+    #[test]
+    fn first_deposit_scales_to_lp_decimals() {
+        assert_eq!(
+            shares_for_deposit(2_000_000, 0, 0, 6, 9).unwrap(),
+            2_000_000_000
+        );
+        assert_eq!(
+            shares_for_deposit(2_000_000_000_000, 0, 0, 12, 9).unwrap(),
+            2_000_000_000
+        );
+        assert_eq!(
+            shares_for_deposit(2_000_000_000, 0, 0, 9, 9).unwrap(),
+            2_000_000_000
+        );
+        fails_with(shares_for_deposit(999, 0, 0, 12, 9), Errors::ZeroShares);
+        fails_with(
+            shares_for_deposit(u64::MAX, 0, 0, 6, 9),
+            Errors::NumericalOverflow,
+        );
+    }
+
+    #[test]
+    fn later_deposits_follow_the_share_price() {
+        // The existing ratio already carries the scaling, so decimals are not applied again
+        assert_eq!(
+            shares_for_deposit(1_000_000, 4_000_000, 4_000_000_000, 6, 9).unwrap(),
+            1_000_000_000
+        );
+        assert_eq!(
+            shares_for_deposit(1_000_000_000_000, 4_000_000_000_000, 4_000_000_000, 12, 9).unwrap(),
+            1_000_000_000
+        );
+        // Yield raises the price, and the result rounds down
+        assert_eq!(shares_for_deposit(100, 300, 200, 9, 9).unwrap(), 66);
+        fails_with(shares_for_deposit(1, 300, 200, 9, 9), Errors::ZeroShares);
+        fails_with(
+            shares_for_deposit(1, 0, 200, 9, 9),
+            Errors::InvalidSharePrice,
+        );
+    }
+
+    #[test]
+    fn deposit_shares_overflow() {
+        // The product is computed in u128, so only a result above u64::MAX overflows
+        assert_eq!(
+            shares_for_deposit(u64::MAX, u64::MAX, u64::MAX, 9, 9).unwrap(),
+            u64::MAX
+        );
+        fails_with(
+            shares_for_deposit(u64::MAX, 1, 2, 9, 9),
+            Errors::NumericalOverflow,
+        );
+    }
+
+    #[test]
+    fn outflow_limit_holds_over_any_window() {
+        let mut outflow = OutflowLimit::default();
+        outflow.set(100, 1_000, 0).unwrap();
+
+        // The full limit at the end of a window, then more half a window later
+        outflow.record(1_000, 99).unwrap();
+        fails_with(outflow.record(500, 150), Errors::OutflowLimitExceeded);
+        fails_with(outflow.record(1, 198), Errors::OutflowLimitExceeded);
+        // Counted for at most a window and a quarter
+        outflow.record(1_000, 225).unwrap();
+        assert_eq!(outflow.current_outflow(225), 1_000);
+    }
+
+    #[test]
+    fn outflow_limit_adds_up_small_withdrawals() {
+        let mut outflow = OutflowLimit::default();
+        outflow.set(100, 1_000, 0).unwrap();
+
+        for now in (0..100).step_by(10) {
+            outflow.record(100, now).unwrap();
+        }
+        fails_with(outflow.record(1, 100), Errors::OutflowLimitExceeded);
+        assert_eq!(outflow.current_outflow(150), 500);
+    }
+
+    #[test]
+    fn outflow_limit_carries_over_to_a_new_window() {
+        let mut outflow = OutflowLimit::default();
+        outflow.set(100, 1_000, 0).unwrap();
+        outflow.record(800, 10).unwrap();
+
+        outflow.set(1_000, 1_000, 20).unwrap();
+        assert_eq!(outflow.current_outflow(500), 800);
+        fails_with(outflow.record(201, 500), Errors::OutflowLimitExceeded);
+
+        outflow.set(1_000, 0, 500).unwrap();
+        assert_eq!(outflow.current_outflow(500), 0);
+    }
+
+    // The vault account of the original program, version 1
+    #[derive(AnchorSerialize)]
+    struct OriginalVault {
+        authority: Pubkey,
+        balance: u64,
+    }
+
+    fn original_vault(authority: Pubkey, balance: u64) -> Vec<u8> {
+        let mut data = Vault::DISCRIMINATOR.to_vec();
+        OriginalVault { authority, balance }.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn original_vault_upgrades_in_place() {
+        let authority = Pubkey::new_unique();
+        let lp_mint = Pubkey::new_unique();
+        let legacy = original_vault(authority, 5_000_000);
+        assert_eq!(legacy.len(), Vault::LEGACY_SIZE);
+
+        // Written over the reallocated account, which must fit the current layout
+        let mut data = vec![0; Vault::SIZE];
+        Vault::from_legacy(&legacy, 254, lp_mint, 9)
+            .unwrap()
+            .try_serialize(&mut &mut data[..])
+            .unwrap();
+
+        let vault = Vault::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(vault.version, ACCOUNT_VERSION);
+        assert_eq!(vault.authority, authority);
+        assert_eq!(vault.balance, 5_000_000);
+        assert_eq!(vault.bump, 254);
+        assert_eq!((vault.lp_mint, vault.lp_decimals), (lp_mint, 9));
+        assert_eq!((vault.fee_manager, vault.asset_manager), (authority, authority));
+        assert!(vault.exit_mode);
+    }
+
+    #[test]
+    fn only_the_original_vault_upgrades() {
+        let legacy = original_vault(Pubkey::new_unique(), 0);
+        let mut current = vec![0; Vault::SIZE];
+        Vault::from_legacy(&legacy, 254, Pubkey::default(), 0)
+            .unwrap()
+            .try_serialize(&mut &mut current[..])
+            .unwrap();
+        fails_with(
+            Vault::from_legacy(&current, 254, Pubkey::default(), 0).map(|_| ()),
+            Errors::UnsupportedAccountVersion,
+        );
+
+        let mut registry = legacy.clone();
+        registry[..8].copy_from_slice(&AssetRegistry::DISCRIMINATOR);
+        fails_with(
+            Vault::from_legacy(&registry, 254, Pubkey::default(), 0).map(|_| ()),
+            Errors::UnsupportedAccountVersion,
+        );
+    }
 }